@@ -0,0 +1,276 @@
+extern crate serde_json;
+
+// Single genome simulated annealing, as an alternative to the darwin-rs
+// population. Worse candidates are accepted with probability
+// exp(-Δ / T) so the search can climb out of local optima.
+
+use lisa::Lisa;
+use context::Context;
+use rando::{Rng, rand};
+use engine::Engine;
+use std::sync::Arc;
+use std::fs::File;
+use std::path::Path;
+
+const STATE_FILE: &'static str = "anneal.json";
+const CHECKPOINT: &'static str = "checkpoint";
+
+/// How the temperature falls over the run
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Schedule {
+    /// Geometric cooling down to 1/10000 of the start by the last step
+    Exponential,
+    /// Straight line down to zero at the last step
+    Linear,
+    /// Exponential, but heat back up (to half the previous peak) when
+    /// nothing has improved for a while
+    Reheat
+}
+
+impl Schedule {
+    pub fn parse(s: &str) -> Option<Schedule> {
+        match s {
+            "exp" => Some(Schedule::Exponential),
+            "linear" => Some(Schedule::Linear),
+            "reheat" => Some(Schedule::Reheat),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Annealer {
+    pub schedule: Schedule,
+    /// Starting temperature, as a fraction of the starting fitness
+    pub t0: f64,
+    pub steps: u64,
+    /// Steps without a new best before reheating
+    pub reheat_after: u64,
+    pub checkpoint_every: u64
+}
+
+/// Everything needed to pick a run back up from a checkpoint
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct AnnealState {
+    schedule: Schedule,
+    step: u64,
+    steps: u64,
+    t0: f64,
+    temperature: f64,
+    peak: f64,
+    stale: u64,
+    rng: Rng
+}
+
+impl Default for Annealer {
+    fn default() -> Annealer {
+        Annealer {
+            schedule: Schedule::Exponential,
+            t0: 0.001,
+            steps: 1_000_000,
+            reheat_after: 20_000,
+            checkpoint_every: 10_000
+        }
+    }
+}
+
+/// A run in progress
+pub struct Anneal {
+    cfg: Annealer,
+    current: Lisa,
+    best: Lisa,
+    fitness: f64,
+    best_fitness: f64,
+    state: AnnealState
+}
+
+impl Annealer {
+    pub fn start(&self, mut start: Lisa) -> Anneal {
+        let t = self.t0 * start.calculate_fitness();
+        let state = AnnealState {
+            schedule: self.schedule,
+            step: 0,
+            steps: self.steps,
+            t0: t,
+            temperature: t,
+            peak: t,
+            stale: 0,
+            rng: start.ctx.rng.lock().unwrap().clone()
+        };
+        let best = start.clone();
+        return Anneal::new(self.clone(), start, best, state);
+    }
+
+    /// Continue from the last checkpoint, and the best saved so far
+    pub fn resume(&self, ctx: Arc<Context>) -> Anneal {
+        let f = File::open(STATE_FILE).expect("no anneal checkpoint to resume");
+        let state: AnnealState = serde_json::from_reader(f).expect("bad anneal checkpoint");
+        let current = Lisa::load(ctx.clone(), &format!("{}.json", CHECKPOINT));
+        let best = if Path::new("best.json").exists() {
+            Lisa::load(ctx, "best.json")
+        } else {
+            current.clone()
+        };
+        println!("# Resuming anneal at step {} of {}, T:{:.1}", state.step, state.steps, state.temperature);
+        return self.restore(current, best, state);
+    }
+
+    /// Pick a run back up from its state, random stream included
+    fn restore(&self, current: Lisa, best: Lisa, state: AnnealState) -> Anneal {
+        *current.ctx.rng.lock().unwrap() = state.rng.clone();
+        return Anneal::new(self.clone(), current, best, state);
+    }
+}
+
+impl Anneal {
+    fn new(cfg: Annealer, mut current: Lisa, mut best: Lisa, state: AnnealState) -> Anneal {
+        let fitness = current.calculate_fitness();
+        let best_fitness = best.calculate_fitness();
+        current.cache_shapes();
+        return Anneal {
+            cfg: cfg,
+            current: current,
+            best: best,
+            fitness: fitness,
+            best_fitness: best_fitness,
+            state: state
+        };
+    }
+
+    fn cool(&mut self) {
+        let s = &mut self.state;
+        match s.schedule {
+            Schedule::Linear => {
+                s.temperature = s.t0 * (1. - s.step as f64 / s.steps as f64).max(0.);
+            },
+            Schedule::Exponential | Schedule::Reheat => {
+                s.temperature *= (1e-4f64).powf(1. / s.steps as f64);
+            }
+        }
+        if s.schedule == Schedule::Reheat && s.stale >= self.cfg.reheat_after {
+            s.peak /= 2.;
+            s.temperature = s.peak;
+            s.stale = 0;
+            println!("# Reheat at step {} to T:{:.1}", s.step, s.temperature);
+        }
+    }
+
+    /// The state to checkpoint, with the random stream as it is now
+    fn snapshot(&mut self) -> AnnealState {
+        self.state.rng = self.current.ctx.rng.lock().unwrap().clone();
+        return self.state.clone();
+    }
+
+    fn checkpoint(&mut self) {
+        println!("# Anneal step {} T:{:.1} current:{:.1} best:{:.1}",
+                 self.state.step, self.state.temperature, self.fitness, self.best_fitness);
+        let state = self.snapshot();
+        self.current.save(CHECKPOINT);
+        let mut f = File::create(STATE_FILE).unwrap();
+        ::std::io::Write::write_all(&mut f,
+                serde_json::to_string(&state).expect("Serialize error").as_bytes()
+            ).expect("couldn't write anneal state");
+    }
+}
+
+impl Engine for Anneal {
+    fn step(&mut self) {
+        self.state.step += 1;
+        let mut candidate = self.current.clone();
+        candidate.mutate();
+        let f = candidate.calculate_fitness();
+        let delta = f - self.fitness;
+        let t = self.state.temperature;
+        let accept = delta < 0. || (t > 0. &&
+            rand(&mut self.current.ctx.rng.lock().unwrap()) < (-delta / t).exp() as f32);
+        if accept {
+            candidate.accept();
+            let unchanged = candidate.shapes.common_prefix(&self.current.shapes);
+            self.current = candidate;
+            self.fitness = f;
+            self.current.cache_from(unchanged);
+        }
+        if self.fitness < self.best_fitness {
+            self.best = self.current.clone();
+            self.best_fitness = self.fitness;
+            self.best.new_fittest_found();
+            self.state.stale = 0;
+        } else {
+            self.state.stale += 1;
+        }
+        self.cool();
+
+        if self.state.step % self.cfg.checkpoint_every == 0 {
+            self.checkpoint();
+        }
+    }
+
+    fn finished(&self) -> bool {
+        return self.state.step >= self.state.steps;
+    }
+
+    fn best(&self) -> &Lisa {
+        return &self.best;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A bare genome in its own context
+    fn bare(seed: u64) -> Lisa {
+        let mut ctx = Context::sample();
+        ctx.reseed(seed);
+        return Lisa::new(Arc::new(ctx));
+    }
+
+    fn cooled(schedule: Schedule, steps: u64) -> Anneal {
+        let annealer = Annealer { schedule: schedule, steps: steps, reheat_after: 10, ..Annealer::default() };
+        let mut a = annealer.start(bare(1));
+        for _ in 0..steps {
+            a.state.step += 1;
+            a.cool();
+        }
+        return a;
+    }
+
+    #[test]
+    fn schedules_end_where_they_should(){
+        let a = cooled(Schedule::Exponential, 1000);
+        assert!((a.state.temperature / (a.state.t0 * 1e-4) - 1.).abs() < 1e-6);
+        let a = cooled(Schedule::Linear, 1000);
+        assert_eq!(a.state.temperature, 0.);
+    }
+
+    #[test]
+    fn reheat_halves_the_peak(){
+        let mut a = cooled(Schedule::Reheat, 100);
+        assert!(a.state.temperature < a.state.t0);
+        a.state.stale = 9;
+        a.cool();
+        assert_eq!(a.state.peak, a.state.t0);
+        a.state.stale = 10;
+        a.cool();
+        assert_eq!(a.state.peak, a.state.t0 / 2.);
+        assert_eq!(a.state.temperature, a.state.peak);
+        assert_eq!(a.state.stale, 0);
+    }
+
+    #[test]
+    fn restore_continues_the_stream(){
+        let annealer = Annealer::default();
+        let mut a = annealer.start(bare(2));
+        for _ in 0..20 {
+            a.current.ctx.rng.lock().unwrap().next_u64();
+        }
+        let saved = serde_json::to_string(&a.snapshot()).unwrap();
+        let next: Vec<u64> = (0..5).map(|_| a.current.ctx.rng.lock().unwrap().next_u64()).collect();
+
+        // A different seed, replaced by the checkpointed stream
+        let r = annealer.restore(bare(3), bare(3), serde_json::from_str(&saved).unwrap());
+        let resumed: Vec<u64> = (0..5).map(|_| r.current.ctx.rng.lock().unwrap().next_u64()).collect();
+        assert_eq!(resumed, next);
+        assert_eq!(r.state.step, a.state.step);
+    }
+}
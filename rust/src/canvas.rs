@@ -5,7 +5,7 @@
 extern crate image;
 use std::path::Path;
 use color::Color;
-use color::color_add;
+use color::{color_add, BlendMode};
 use std::cmp::{min, max};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -31,6 +31,20 @@ impl Canvas {
 		}
 	}
 
+    pub fn with_background(width: usize, height: usize, depth: usize, color: &Color) -> Canvas {
+        let mut canv = Canvas::new(width, height, depth);
+        for i in 0..width * height {
+            if depth == 1 {
+                canv.pixels[i] = color.r;
+            } else {
+                canv.pixels[i * depth] = color.r;
+                canv.pixels[i * depth + 1] = color.g;
+                canv.pixels[i * depth + 2] = color.b;
+            }
+        }
+        return canv;
+    }
+
     pub fn from(width: usize, height:usize, depth:usize, data: Vec<u8>) -> Canvas {
 		let mut vec = Vec::with_capacity(data.len());
 		for i in 0..data.len() {
@@ -70,8 +84,35 @@ impl Canvas {
         }
     }
 
+    /// Overwrite a pixel, for opaque colours
+    #[inline]
+    pub fn set_pixel(&mut self, x: i32, y: i32, color: &Color) {
+        let i = self.ind_from_pos(x, y) as usize;
+        if self.depth == 1 {
+            self.pixels[i] = color.r;
+        } else {
+            color.set_in_vec(&mut self.pixels, i);
+        }
+    }
+
+    #[inline]
+    pub fn blend_pixel(&mut self, x: i32, y: i32, color: &Color, mode: BlendMode) {
+        if mode == BlendMode::Normal {
+            if color.opacity >= 1. {
+                return self.set_pixel(x, y, color);
+            }
+            return self.add_pixel(x, y, color);
+        }
+        let i = self.ind_from_pos(x, y) as usize;
+        if self.depth == 1 {
+            self.pixels[i] = mode.blend(self.pixels[i], color.r, color.opacity);
+        } else {
+            color.blend_to_vec(&mut self.pixels, i, mode);
+        }
+    }
+
     #[inline]
-    pub fn line_add(&mut self, x1: i32, x2: i32, y: i32, color: &Color){
+    pub fn line_add(&mut self, x1: i32, x2: i32, y: i32, color: &Color, mode: BlendMode){
         let wid = self.width as i32;
         if y < 0 || y >= self.height as i32 {
             return;
@@ -79,7 +120,7 @@ impl Canvas {
         let xmin = max(0, x1);
         let xmax = min(wid - 1, x2); 
         for x in xmin .. xmax + 1 {
-            self.add_pixel(x, y, color);
+            self.blend_pixel(x, y, color, mode);
         }
     }
 
@@ -127,7 +168,7 @@ impl Canvas {
     }
 
     pub fn pixel_at(&self, x: i32, y: i32) -> Color {
-        if x > 0 && x < self.width as i32 && y > 0 && y < self.height as i32 {
+        if x >= 0 && x < self.width as i32 && y >= 0 && y < self.height as i32 {
             let i = self.ind_from_pos(x, y) as usize;
             if self.depth == 1 {
                 return Color { r: self.pixels[i],
@@ -147,6 +188,55 @@ impl Canvas {
         }
     }
 
+    /// Average colour of the whole canvas
+    pub fn mean_color(&self) -> Color {
+        let n = (self.width * self.height) as f64;
+        let mut totals = [0f64; 3];
+        for px in self.pixels.chunks(self.depth) {
+            for (c, total) in totals.iter_mut().enumerate() {
+                *total += px[min(c, self.depth - 1)] as f64;
+            }
+        }
+        return Color {
+            r: (totals[0] / n) as f32,
+            g: (totals[1] / n) as f32,
+            b: (totals[2] / n) as f32,
+            opacity: 1.
+        }
+    }
+
+    /// Squared difference to the same pixel of another canvas
+    pub fn pixel_diff_sq_with(&self, canv: &Canvas, x: i32, y: i32) -> i32 {
+        let p1 = self.pixel_at(x, y);
+        let p2 = canv.pixel_at(x, y);
+        return (p1.r as i32 - p2.r as i32) * (p1.r as i32 - p2.r as i32) +
+               (p1.g as i32 - p2.g as i32) * (p1.g as i32 - p2.g as i32) +
+               (p1.b as i32 - p2.b as i32) * (p1.b as i32 - p2.b as i32) ;
+    }
+
+    /// Average colour of the pixels covered by a depth 1 mask
+    pub fn mean_color_under(&self, mask: &Canvas) -> Option<Color> {
+        let mut totals = [0f64; 3];
+        let mut n = 0;
+        for (px, &m) in self.pixels.chunks(self.depth).zip(mask.pixels.iter()) {
+            if m > 0. {
+                for (c, total) in totals.iter_mut().enumerate() {
+                    *total += px[min(c, self.depth - 1)] as f64;
+                }
+                n += 1;
+            }
+        }
+        if n == 0 {
+            return None;
+        }
+        return Some(Color {
+            r: (totals[0] / n as f64) as f32,
+            g: (totals[1] / n as f64) as f32,
+            b: (totals[2] / n as f64) as f32,
+            opacity: 1.
+        })
+    }
+
     pub fn pixel_diff_sq(&self, x1: i32, y1:i32, x2:i32, y2:i32) -> i32{
         let p1 = self.pixel_at(x1, y1);
         let p2 = self.pixel_at(x2, y2);
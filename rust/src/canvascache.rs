@@ -5,62 +5,106 @@ extern crate fnv;
 use canvas::{Canvas};
 use self::lru_cache::LruCache;
 use shapelist::{ShapeList};
-use shapes::Shape;
-use std::hash::{BuildHasherDefault};
+use std::hash::{BuildHasherDefault, Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use self::fnv::FnvHasher;
 
+/// Prefixes are spread over this many independently locked shards by
+/// hash, so threads looking up different prefixes rarely wait on each
+/// other.
+const SHARDS: usize = 16;
+const CAPACITY: usize = 1024;
+
+/// Canvases are shared out of the shard so they can be copied after the
+/// lock is released
+type Shard = LruCache<ShapeList, Arc<Canvas>, BuildHasherDefault<FnvHasher>>;
+
 #[derive(Debug)]
 pub struct CanvasCache {
-    map: LruCache<Vec<Shape>, Canvas, BuildHasherDefault<FnvHasher>>,
+    shards: Vec<Mutex<Shard>>,
     width: usize,
     height: usize,
     depth: usize,
-    hits: usize,
-    misses: usize,
-    requests: usize,
-    shapes: usize
-}
-
-fn fallback_for(sl: &ShapeList, width:usize, height:usize, depth:usize) -> Canvas {
-    let mut canv = Canvas::new(width, height, depth);
-    sl.draw_onto(&mut canv);
-    return canv;
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    requests: AtomicUsize,
+    shapes: AtomicUsize
 }
 
 impl CanvasCache {
 
     pub fn new(width: usize, height: usize, depth: usize) -> CanvasCache {
         CanvasCache {
-            map: LruCache::with_hasher(
-                     1000,
-                     BuildHasherDefault::<FnvHasher>::default()),
+            shards: (0..SHARDS).map(|_| Mutex::new(LruCache::with_hasher(
+                     CAPACITY / SHARDS,
+                     BuildHasherDefault::<FnvHasher>::default()))).collect(),
             width: width,
             height: height,
             depth: depth,
-            hits: 0,
-            misses: 0,
-            requests: 0,
-            shapes: 0
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+            requests: AtomicUsize::new(0),
+            shapes: AtomicUsize::new(0)
         }
-    }   
+    }
+
+    fn shard(&self, sl: &ShapeList) -> &Mutex<Shard> {
+        let mut h = FnvHasher::default();
+        sl.hash(&mut h);
+        return &self.shards[h.finish() as usize % SHARDS];
+    }
+
+    fn get(&self, sl: &ShapeList) -> Option<Canvas> {
+        let found = self.shard(sl).lock().unwrap().get_mut(sl).map(|c| c.clone());
+        return found.map(|c| (*c).clone());
+    }
+
+    fn put(&self, sl: ShapeList, canv: Canvas) {
+        self.shard(&sl).lock().unwrap().insert(sl, Arc::new(canv));
+    }
+
+    pub fn len(&self) -> usize {
+        return self.shards.iter().map(|s| s.lock().unwrap().len()).sum();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.shards.iter().all(|s| s.lock().unwrap().is_empty());
+    }
 
-    pub fn canvas_for(&mut self, sl: &ShapeList) -> Canvas {
-        self.requests += 1;
-        if (self.requests) % 1000 == 0 {
+    pub fn canvas_for(&self, sl: &ShapeList) -> Canvas {
+        let requests = self.requests.fetch_add(1, Ordering::Relaxed) + 1;
+        if requests % 1000 == 0 {
             let now = chrono::Utc::now();
-            print!("{} Cache: hits:{} misses: {} req: {} shp: {}  len: {}\n", 
-                  now, self.hits, self.misses, self.requests, self.shapes, self.map.len());
+            print!("{} Cache: hits:{} misses: {} req: {} shp: {}  len: {}\n",
+                  now, self.hits.load(Ordering::Relaxed), self.misses.load(Ordering::Relaxed),
+                  requests, self.shapes.load(Ordering::Relaxed), self.len());
+        }
+        let (mut c, start) = self.search_sublist(sl);
+        for x in start..sl.len() {
+            sl.draw_item_onto(x, &mut c);
         }
-        return self.search_sublist(sl);
+        return c;
     }
 
-    /// Insert a shapelist and all subportions of that shapelist
-    pub fn insert(&mut self, sl: &ShapeList) {
-        let mut canv = Canvas::new(self.width, self.height, self.depth);
-        for i in 0..sl.len() {
-            let s = sl.slice(i + 1);
+    /// Insert a shapelist and all subportions of that shapelist,
+    /// drawing on from the longest portion already cached
+    pub fn insert(&self, sl: &ShapeList) {
+        let (mut canv, start) = self.search_sublist(sl);
+        for i in start..sl.len() {
+            sl.draw_item_onto(i, &mut canv);
+            self.put(sl.slice(i + 1), canv.clone());
+        }
+    }
+
+    /// As `insert`, when the first `from` shapes are unchanged since the
+    /// list was last cached, so only the prefixes above them are drawn
+    pub fn insert_from(&self, sl: &ShapeList, from: usize) {
+        let from = from.min(sl.len());
+        let mut canv = self.canvas_for(&sl.slice(from));
+        for i in from..sl.len() {
             sl.draw_item_onto(i, &mut canv);
-            self.map.insert(s.shapes.clone(), canv.clone()); 
+            self.put(sl.slice(i + 1), canv.clone());
         }
     }
 
@@ -72,25 +116,59 @@ impl CanvasCache {
     /// from there.
     ///
     /// This means that mutations to a shapelist are cheaper towards the end
-    pub fn search_sublist(&mut self, sl: &ShapeList) -> Canvas {
+    ///
+    /// Returns the canvas for the longest cached prefix and its length,
+    /// or a blank canvas and 0.
+    pub fn search_sublist(&self, sl: &ShapeList) -> (Canvas, usize) {
         for i in 0..sl.len() {
-            let k = &sl.slice(sl.len() - i).shapes;
-            match self.map.get_mut(k){
-                Some(k) => { 
-                    self.hits += 1;
-                    self.shapes += i;
-                    let mut c = k.clone();
-                    for x in (sl.len() - i)..sl.len() {
-                        sl.draw_item_onto(x, &mut c);
-                    }
-                    return c;
-                },
-                None => {}
+            let k = sl.slice(sl.len() - i);
+            if let Some(c) = self.get(&k) {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                self.shapes.fetch_add(i, Ordering::Relaxed);
+                return (c, sl.len() - i);
             }
         }
-        
-        // None found, 
-        self.misses += 1;
-        return fallback_for(&sl, self.width, self.height, self.depth);
+
+        // None found,
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        return (sl.blank_canvas(self.width, self.height, self.depth), 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CanvasCache;
+    use shapelist::ShapeList;
+    use shapes::Shape;
+    use color::Color;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn genome(n: usize) -> ShapeList {
+        let mut sl = ShapeList::new();
+        for i in 0..n {
+            sl.shapes.push(Shape::circle(i as f32 / n as f32, 0.5, 0.2,
+                                         Color { r: 200., g: 100., b: 50., opacity: 0.5 }));
+        }
+        return sl;
+    }
+
+    #[test]
+    fn concurrent_lookups_match_a_fresh_render(){
+        let sl = genome(40);
+        let mut expected = sl.blank_canvas(32, 32, 3);
+        sl.draw_onto(&mut expected);
+
+        let cache = Arc::new(CanvasCache::new(32, 32, 3));
+        cache.insert(&sl.slice(30));
+        let handles: Vec<_> = (0..4).map(|_| {
+            let cache = cache.clone();
+            let sl = sl.clone();
+            thread::spawn(move || cache.canvas_for(&sl))
+        }).collect();
+        for h in handles {
+            assert!(h.join().unwrap().get_pixels() == expected.get_pixels());
+        }
+        assert_eq!(cache.search_sublist(&sl).1, 30);
     }
 }
@@ -1,14 +1,72 @@
 use std::fmt::Write;
 use std::fmt;
 use std::ops::{Mul, Add};
-use rando::{rand_adjust, rand_color_adjust};
+use rando::{Rng, rand, rand_adjust, rand_color_adjust};
 use std::hash::{Hash, Hasher};
+use steps::StepSizes;
 
 #[inline]
 pub fn color_add(c:f32, c2: f32, opacity: f32) -> f32 {
 	return c * (1. - opacity) + (c2 * opacity);
 }
 
+/// How a shape's colour is combined with the canvas beneath it, before
+/// being composited alpha-over with the shape's opacity.
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum BlendMode {
+    Normal,
+    Multiply,
+    Screen,
+    Additive,
+    Darken,
+    Lighten
+}
+
+impl Default for BlendMode {
+    fn default() -> BlendMode {
+        BlendMode::Normal
+    }
+}
+
+impl BlendMode {
+    pub fn random(rng: &mut Rng) -> BlendMode {
+        match (rand(rng) * 6.) as u8 {
+            0 => BlendMode::Normal,
+            1 => BlendMode::Multiply,
+            2 => BlendMode::Screen,
+            3 => BlendMode::Additive,
+            4 => BlendMode::Darken,
+            _ => BlendMode::Lighten
+        }
+    }
+
+    /// Blend a single channel: `c` is the canvas, `c2` the shape.
+    #[inline]
+    pub fn blend(&self, c: f32, c2: f32, opacity: f32) -> f32 {
+        let b = match *self {
+            BlendMode::Normal => c2,
+            BlendMode::Multiply => c * c2 / 255.,
+            BlendMode::Screen => 255. - (255. - c) * (255. - c2) / 255.,
+            BlendMode::Additive => (c + c2).min(255.),
+            BlendMode::Darken => c.min(c2),
+            BlendMode::Lighten => c.max(c2)
+        };
+        return color_add(c, b, opacity);
+    }
+
+    /// Style attribute for the svg element, empty for normal compositing.
+    pub fn svg(&self) -> &'static str {
+        match *self {
+            BlendMode::Normal => "",
+            BlendMode::Multiply => " style='mix-blend-mode: multiply'",
+            BlendMode::Screen => " style='mix-blend-mode: screen'",
+            BlendMode::Additive => " style='mix-blend-mode: plus-lighter'",
+            BlendMode::Darken => " style='mix-blend-mode: darken'",
+            BlendMode::Lighten => " style='mix-blend-mode: lighten'"
+        }
+    }
+}
+
 #[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
 pub struct Color {
     pub r: f32, // 0 - 255 (u8)
@@ -19,15 +77,20 @@ pub struct Color {
 
 impl Color {
     pub fn svg(&self, depth: usize) -> String {
-        if depth == 1 {
-            let mut out = String::new();
-            write!(&mut out,
-                   "rgba({},{},{},{:.4})",
-                    self.r as u8, self.r as u8, self.r as u8, self.opacity)
+        let g = if depth == 1 { self.r } else { self.g };
+        let b = if depth == 1 { self.r } else { self.b };
+        let mut out = String::new();
+        if self.opacity >= 1. {
+            // Flat colours don't need an alpha channel
+            write!(&mut out, "rgb({},{},{})", self.r as u8, g as u8, b as u8)
                 .expect("String concat failed");
             return out;
         }
-        return self.rgba()
+        write!(&mut out,
+               "rgba({},{},{},{:.4})",
+                self.r as u8, g as u8, b as u8, self.opacity)
+            .expect("String concat failed");
+        return out;
     }
 
 
@@ -40,12 +103,12 @@ impl Color {
         return rgb;
     }
 
-    pub fn mutate(&self) -> Color{
+    pub fn mutate(&self, steps: &StepSizes, rng: &mut Rng) -> Color{
         return Color{
-            r: rand_color_adjust(self.r, 0.1),
-            g: rand_color_adjust(self.g, 0.1),
-            b: rand_color_adjust(self.b, 0.1),
-            opacity: rand_adjust(self.opacity, 0.1, 0., 1.),
+            r: rand_color_adjust(rng, self.r, steps.color),
+            g: rand_color_adjust(rng, self.g, steps.color),
+            b: rand_color_adjust(rng, self.b, steps.color),
+            opacity: rand_adjust(rng, self.opacity, steps.opacity, 0., 1.),
         }
     }
 
@@ -56,9 +119,49 @@ impl Color {
         vec[i + 2] = color_add(vec[i + 2],  self.b, self.opacity);
     }
 
+    #[inline]
+    pub fn set_in_vec(&self, vec: &mut Vec<f32>, i: usize){
+        vec[i]     = self.r;
+        vec[i + 1] = self.g;
+        vec[i + 2] = self.b;
+    }
+
+    #[inline]
+    pub fn blend_to_vec(&self, vec: &mut Vec<f32>, i: usize, mode: BlendMode){
+        vec[i]     = mode.blend(vec[i],      self.r, self.opacity);
+        vec[i + 1] = mode.blend(vec[i + 1],  self.g, self.opacity);
+        vec[i + 2] = mode.blend(vec[i + 2],  self.b, self.opacity);
+    }
+
+    /// Blend towards `other` by `weight` (0 - 1), opacity included
+    pub fn mix(&self, other: &Color, weight: f32) -> Color {
+        return &(self.clone() * (1. - weight)) + &(other.clone() * weight);
+    }
+
+    /// Mean absolute channel difference, 0 - 1
+    pub fn distance(&self, other: &Color) -> f32 {
+        return ((self.r - other.r).abs() +
+                (self.g - other.g).abs() +
+                (self.b - other.b).abs()) / (3. * 255.);
+    }
+
     pub fn black() -> Color {
         Color {r:0.,g:0.,b:0.,opacity:1.}
     }
+
+    /// Channels scaled to 0 - 1, opacity last
+    pub fn params(&self) -> [f32; 4] {
+        return [self.r / 255., self.g / 255., self.b / 255., self.opacity];
+    }
+
+    pub fn from_params(p: &[f32]) -> Color {
+        return Color {
+            r: (p[0] * 255.).max(0.).min(255.),
+            g: (p[1] * 255.).max(0.).min(255.),
+            b: (p[2] * 255.).max(0.).min(255.),
+            opacity: p[3].max(0.).min(1.)
+        };
+    }
 }
 
 impl fmt::Display for Color {
@@ -104,6 +207,8 @@ impl Hash for Color {
     }
 }
 
+impl Eq for Color {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -113,4 +218,14 @@ mod tests {
         let c = color_add(255., 255., 1.);
         assert_eq!(c, 255);
     }
+
+    #[test]
+    fn test_blend_modes() {
+        assert_eq!(BlendMode::Normal.blend(100., 200., 1.), 200.);
+        assert_eq!(BlendMode::Multiply.blend(255., 100., 1.), 100.);
+        assert_eq!(BlendMode::Screen.blend(0., 100., 1.), 100.);
+        assert_eq!(BlendMode::Additive.blend(200., 100., 1.), 255.);
+        assert_eq!(BlendMode::Darken.blend(50., 100., 0.5), 50.);
+        assert_eq!(BlendMode::Lighten.blend(50., 100., 0.5), 75.);
+    }
 }
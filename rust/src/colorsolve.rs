@@ -0,0 +1,93 @@
+// Closed form colour for a shape of fixed geometry.
+//
+// A shape of colour C and opacity a composited over a canvas B gives
+// (1 - a)B + aC. Over the pixels it covers, the least squares fit to the
+// target T has 1 - a equal to the regression slope of T on B, and then
+// C = mean(B) + (mean(T) - mean(B)) / a.
+
+use canvas::Canvas;
+use color::Color;
+
+const MIN_OPACITY: f32 = 0.05;
+
+/// Best colour for the pixels set in `mask`, drawn over `below`. If
+/// `opacity` is given it is held fixed, otherwise it is solved for too,
+/// falling back to `current` where it can't be determined.
+pub fn solve(below: &Canvas, target: &Canvas, mask: &Canvas,
+             opacity: Option<f32>, current: f32) -> Option<Color> {
+    let mut n = 0.;
+    let mut sb = [0f64; 3];
+    let mut st = [0f64; 3];
+    let mut sbb = [0f64; 3];
+    let mut sbt = [0f64; 3];
+
+    for y in 0..mask.height as i32 {
+        for x in 0..mask.width as i32 {
+            if mask.pixel_at(x, y).r <= 0. {
+                continue;
+            }
+            let b = below.pixel_at(x, y);
+            let t = target.pixel_at(x, y);
+            let bs = [b.r as f64, b.g as f64, b.b as f64];
+            let ts = [t.r as f64, t.g as f64, t.b as f64];
+            for c in 0..3 {
+                sb[c] += bs[c];
+                st[c] += ts[c];
+                sbb[c] += bs[c] * bs[c];
+                sbt[c] += bs[c] * ts[c];
+            }
+            n += 1.;
+        }
+    }
+    if n == 0. {
+        return None;
+    }
+
+    let a = match opacity {
+        Some(a) => a,
+        None => {
+            let mut cov = 0.;
+            let mut var = 0.;
+            for c in 0..3 {
+                cov += sbt[c] - sb[c] * st[c] / n;
+                var += sbb[c] - sb[c] * sb[c] / n;
+            }
+            if var < 1e-6 {
+                current
+            } else {
+                ((1. - cov / var) as f32).min(1.).max(MIN_OPACITY)
+            }
+        }
+    };
+
+    let channel = |c: usize| {
+        let mb = sb[c] / n;
+        let mt = st[c] / n;
+        return ((mb + (mt - mb) / a as f64) as f32).min(255.).max(0.);
+    };
+    return Some(Color { r: channel(0), g: channel(1), b: channel(2), opacity: a });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use color::BlendMode;
+
+    #[test]
+    fn recovers_color() {
+        let mut below = Canvas::new(4, 1, 3);
+        let mut target = Canvas::new(4, 1, 3);
+        let mut mask = Canvas::new(4, 1, 1);
+        let shape = Color { r: 200., g: 100., b: 0., opacity: 0.5 };
+        for x in 0..4 {
+            let b = Color { r: x as f32 * 50., g: 20., b: 255. - x as f32 * 50., opacity: 1. };
+            below.set_pixel(x, 0, &b);
+            target.set_pixel(x, 0, &b);
+            target.blend_pixel(x, 0, &shape, BlendMode::Normal);
+            mask.set_pixel(x, 0, &Color { r: 255., g: 255., b: 255., opacity: 1. });
+        }
+        let c = solve(&below, &target, &mask, None, 1.).unwrap();
+        assert!((c.opacity - 0.5).abs() < 0.01);
+        assert!((c.r - 200.).abs() < 1. && (c.g - 100.).abs() < 1. && c.b < 1.);
+    }
+}
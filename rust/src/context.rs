@@ -7,11 +7,18 @@ use canvas::{Canvas};
 use std::io::BufReader;
 use canvascache::CanvasCache;
 use std::sync::{Arc, Mutex};
+use palette::Palette;
+use rates::{MutationRates, OperatorStats};
+use pool::BreedingPool;
+use lineage::Lineage;
+use rando::Rng;
+use stagnation::Restart;
 
 #[derive(Debug, Clone)]
 pub struct Context {
 	pub image: Canvas,
     pub weightings: Canvas,
+    pub mean_color: Color,
 	pub width: i32,
 	pub height: i32,
     pub depth: i32,
@@ -21,7 +28,31 @@ pub struct Context {
     pub use_triangles: bool,
     pub use_circles: bool,
     pub use_rectangles: bool,
-    pub cache: Arc<Mutex<CanvasCache>>
+    /// How far shape coordinates may reach outside the image, as a
+    /// fraction of its size.
+    pub margin: f32,
+    /// Restrict shape colours to these entries
+    pub palette: Option<Palette>,
+    /// Fixed opacity for palette colours
+    pub palette_opacity: Option<f32>,
+    /// Flat colours only, every shape is fully opaque
+    pub opaque: bool,
+    /// Solve for the best colour after geometric mutations
+    pub solve_colors: bool,
+    pub rates: MutationRates,
+    /// Adapt operator probabilities to their acceptance rates
+    pub adaptive_rates: bool,
+    pub operators: Arc<OperatorStats>,
+    /// What resetting a stagnant genome does
+    pub restart: Restart,
+    pub breeding_pool: Arc<Mutex<BreedingPool>>,
+    /// Where to log each new best, if anywhere
+    pub lineage: Option<Arc<Mutex<Lineage>>>,
+    pub seed: u64,
+    pub rng: Arc<Mutex<Rng>>,
+    /// Fitness of the best genome written out, shared between islands
+    pub best_fitness: Arc<Mutex<f64>>,
+    pub cache: Arc<CanvasCache>
 }
 
 impl Context {
@@ -35,14 +66,17 @@ impl Context {
 		let mut jpg = Decoder::new(buf);
 		let image = jpg.decode().expect("failed to decode image");
 		let meta = jpg.info().unwrap();
+        let seed = Rng::entropy_seed();
         let depth = match meta.pixel_format {
             jpeg_decoder::PixelFormat::RGB24 => 3,
             jpeg_decoder::PixelFormat::L8 => 1,
             jpeg_decoder::PixelFormat::CMYK32 => 4
         };
 
+        let image = Canvas::from(meta.width as usize, meta.height as usize, depth, image);
 		return Context {
-			image: Canvas::from(meta.width as usize, meta.height as usize, depth, image),
+            mean_color: image.mean_color(),
+			image: image,
             weightings: Canvas::new(meta.width as usize, meta.height as usize, 3),
 			height: meta.height as i32,
 			width: meta.width as i32,
@@ -53,10 +87,53 @@ impl Context {
             use_triangles: use_triangles,
             use_circles: use_circles,
             use_rectangles: use_rectangles,
-            cache: Arc::new(Mutex::new(CanvasCache::new(meta.width as usize, meta.height as usize, depth)))
+            margin: 0.,
+            palette: None,
+            palette_opacity: None,
+            opaque: false,
+            solve_colors: false,
+            rates: MutationRates::default(),
+            adaptive_rates: false,
+            operators: Arc::new(OperatorStats::new()),
+            restart: Restart::default(),
+            breeding_pool: Arc::new(Mutex::new(BreedingPool::new(10))),
+            lineage: None,
+            seed: seed,
+            rng: Arc::new(Mutex::new(Rng::new(seed))),
+            best_fitness: Arc::new(Mutex::new(::std::f64::INFINITY)),
+            cache: Arc::new(CanvasCache::new(meta.width as usize, meta.height as usize, depth))
 		};
 	}
 
+    /// The target image from the couchdb version, for tests
+    #[cfg(test)]
+    pub fn sample() -> Context {
+        let image = concat!(env!("CARGO_MANIFEST_DIR"), "/../couch-db/_attachments/image/lisa.jpg");
+        let mut ctx = Context::new(image, false, true, true, true);
+        ctx.reseed(1);
+        return ctx;
+    }
+
+    /// Restart the random stream from `seed`
+    pub fn reseed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng = Arc::new(Mutex::new(Rng::new(seed)));
+    }
+
+    /// A copy for island `i` with its own cache, random stream,
+    /// breeding pool and operator stats. The best found so far and the
+    /// lineage stay shared.
+    pub fn island(&self, i: usize) -> Context {
+        let mut c = self.clone();
+        c.rng = Arc::new(Mutex::new(Rng::new(self.seed.wrapping_add(i as u64 + 1))));
+        c.cache = Arc::new(CanvasCache::new(self.width as usize,
+                                            self.height as usize,
+                                            self.depth as usize));
+        c.breeding_pool = Arc::new(Mutex::new(BreedingPool::new(10)));
+        c.operators = Arc::new(OperatorStats::new());
+        return c;
+    }
+
     pub fn weight_entropy(&mut self) {
         // Copy image
         for x in 0..self.width {
@@ -0,0 +1,262 @@
+// Differential evolution over the continuous genes of a fixed genome.
+// Shapes are optimised a window at a time so the dimension stays small,
+// sliding down from the top of the stack where probes are cheapest.
+
+use lisa::Lisa;
+use shapes::Shape;
+use refine::{tunable, fixed_opacity};
+use evolution::{Member, evaluate};
+use rando::{Rng, rand, rand_gauss};
+
+#[derive(Debug, Clone)]
+pub struct DeConfig {
+    /// Shapes optimised together
+    pub window: usize,
+    /// Shapes the window moves down by each time
+    pub stride: usize,
+    /// Candidate vectors per window
+    pub population: usize,
+    pub generations: usize,
+    /// Differential weight
+    pub f: f32,
+    /// Crossover probability
+    pub cr: f32,
+    /// Spread of the starting population around the current genome
+    pub sigma: f32,
+    pub threads: usize
+}
+
+impl Default for DeConfig {
+    fn default() -> DeConfig {
+        DeConfig {
+            window: 4,
+            stride: 2,
+            population: 12,
+            generations: 30,
+            f: 0.5,
+            cr: 0.9,
+            sigma: 0.02,
+            threads: 1
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DeReport {
+    pub windows: usize,
+    pub improved: usize,
+    pub fitness_before: f64,
+    pub fitness_after: f64
+}
+
+/// Where each entry of the window vector lives: (shape, param)
+fn layout(shapes: &[Shape], start: usize, palette: bool, fixed_opacity: bool) -> Vec<(usize, usize)> {
+    let mut out = Vec::new();
+    for (k, s) in shapes.iter().enumerate() {
+        for j in tunable(s.params().len(), palette, fixed_opacity) {
+            out.push((start + k, j));
+        }
+    }
+    return out;
+}
+
+fn apply(lisa: &Lisa, layout: &[(usize, usize)], x: &[f32]) -> Lisa {
+    let mut out = lisa.clone();
+    let mut i = 0;
+    while i < layout.len() {
+        let shape = layout[i].0;
+        let mut p = out.shapes.shapes[shape].params();
+        while i < layout.len() && layout[i].0 == shape {
+            p[layout[i].1] = x[i];
+            i += 1;
+        }
+        out.shapes.shapes[shape].set_params(&p, lisa.ctx.margin);
+    }
+    return out;
+}
+
+/// Three distinct indices other than `i`
+fn pick3(n: usize, i: usize, rng: &mut Rng) -> (usize, usize, usize) {
+    let mut pick = |not: &[usize]| loop {
+        let j = (rand(rng) * n as f32) as usize;
+        if !not.contains(&j) {
+            return j;
+        }
+    };
+    let a = pick(&[i]);
+    let b = pick(&[i, a]);
+    let c = pick(&[i, a, b]);
+    return (a, b, c);
+}
+
+/// DE/rand/1/bin on one window, returning the improved genome if any
+fn optimise_window(lisa: &Lisa, fitness: f64, start: usize, cfg: &DeConfig) -> Option<(Lisa, f64)> {
+    let ctx = lisa.ctx.clone();
+    let end = (start + cfg.window).min(lisa.shapes.len());
+    let layout = layout(&lisa.shapes.shapes[start..end], start,
+                        ctx.palette.is_some(), fixed_opacity(&ctx));
+    let dim = layout.len();
+    let np = cfg.population.max(4);
+    if dim == 0 {
+        return None;
+    }
+    let x0: Vec<f32> = layout.iter().map(|&(s, j)| lisa.shapes.shapes[s].params()[j]).collect();
+    let bounds: Vec<(f32, f32)> = layout.iter()
+        .map(|&(s, j)| lisa.shapes.shapes[s].param_bounds(ctx.margin)[j]).collect();
+    let clamp = |j: usize, v: f32| v.max(bounds[j].0).min(bounds[j].1);
+
+    let mut xs = vec![x0.clone()];
+    {
+        let mut rng = ctx.rng.lock().unwrap();
+        for _ in 1..np {
+            xs.push(x0.iter().enumerate().map(|(j, v)| clamp(j, v + rand_gauss(&mut rng) * cfg.sigma)).collect());
+        }
+    }
+    let mut scored: Vec<Member> = xs.iter().map(|x| Member { lisa: apply(lisa, &layout, x), fitness: 0. }).collect();
+    scored[0].fitness = fitness;
+    evaluate(&mut scored[1..], cfg.threads);
+    let mut fit: Vec<f64> = scored.iter().map(|m| m.fitness).collect();
+
+    for _ in 0..cfg.generations {
+        let mut trials = Vec::with_capacity(np);
+        {
+            let mut rng = ctx.rng.lock().unwrap();
+            for i in 0..np {
+                let (a, b, c) = pick3(np, i, &mut rng);
+                let jrand = (rand(&mut rng) * dim as f32) as usize;
+                let t: Vec<f32> = (0..dim).map(|j| {
+                    if j == jrand || rand(&mut rng) < cfg.cr {
+                        clamp(j, xs[a][j] + cfg.f * (xs[b][j] - xs[c][j]))
+                    } else {
+                        xs[i][j]
+                    }
+                }).collect();
+                trials.push(t);
+            }
+        }
+        let mut scored: Vec<Member> = trials.iter().map(|t| Member { lisa: apply(lisa, &layout, t), fitness: 0. }).collect();
+        evaluate(&mut scored, cfg.threads);
+        for (i, m) in scored.iter().enumerate() {
+            if m.fitness <= fit[i] {
+                xs[i] = trials[i].clone();
+                fit[i] = m.fitness;
+            }
+        }
+    }
+
+    let mut best = 0;
+    for i in 1..np {
+        if fit[i] < fit[best] {
+            best = i;
+        }
+    }
+    if fit[best] < fitness {
+        return Some((apply(lisa, &layout, &xs[best]), fit[best]));
+    }
+    return None;
+}
+
+pub fn optimise(lisa: &mut Lisa, cfg: &DeConfig) -> DeReport {
+    let fitness_before = lisa.calculate_fitness();
+    let mut current = fitness_before;
+    let mut windows = 0;
+    let mut improved = 0;
+    lisa.cache_shapes();
+
+    let l = lisa.shapes.len();
+    let window = cfg.window.max(1);
+    let mut start = l.saturating_sub(window);
+    loop {
+        windows += 1;
+        if let Some((better, f)) = optimise_window(lisa, current, start, cfg) {
+            *lisa = better;
+            current = f;
+            improved += 1;
+            lisa.cache_from(start);
+        }
+        println!("# DE window {}..{}: {:.1}", start, (start + window).min(l), current);
+        if start == 0 {
+            break;
+        }
+        start = start.saturating_sub(cfg.stride.max(1));
+    }
+
+    return DeReport {
+        windows: windows,
+        improved: improved,
+        fitness_before: fitness_before,
+        fitness_after: current
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pick3, optimise, optimise_window, DeConfig};
+    use lisa::Lisa;
+    use context::Context;
+    use shapes::{Shape, Rect};
+    use color::Color;
+    use rando::Rng;
+    use std::sync::Arc;
+
+    fn genome() -> Lisa {
+        let mut lisa = Lisa::new(Arc::new(Context::sample()));
+        lisa.shapes.background = Color::black();
+        let color = Color { r: 120., g: 90., b: 60., opacity: 0.6 };
+        lisa.shapes.shapes.push(Shape::Rect(Rect {
+            x: 0.1, y: 0.1, width: 0.5, height: 0.4, color: color.clone(),
+            blend: Default::default(), steps: Default::default()
+        }));
+        lisa.shapes.shapes.push(Shape::circle(0.95, 0.5, 0.3, color.clone()));
+        lisa.shapes.shapes.push(Shape::Rect(Rect {
+            x: 0.5, y: 0.6, width: 0.4, height: 0.4, color: color,
+            blend: Default::default(), steps: Default::default()
+        }));
+        return lisa;
+    }
+
+    fn same_structure(a: &Lisa, b: &Lisa) -> bool {
+        return a.shapes.len() == b.shapes.len() &&
+            a.shapes.shapes.iter().zip(b.shapes.shapes.iter())
+                .all(|(s, t)| s.distance(t).is_some() && s.blend() == t.blend());
+    }
+
+    #[test]
+    fn picks_distinct_donors(){
+        let mut rng = Rng::new(9);
+        for i in 0..4 {
+            for _ in 0..20 {
+                let (a, b, c) = pick3(4, i, &mut rng);
+                let mut all = vec![i, a, b, c];
+                all.sort();
+                all.dedup();
+                assert_eq!(all.len(), 4);
+            }
+        }
+    }
+
+    #[test]
+    fn windows_only_improve(){
+        let cfg = DeConfig { window: 2, stride: 1, population: 6, generations: 4, sigma: 0.2, ..DeConfig::default() };
+        let mut lisa = genome();
+        let before = lisa.clone();
+        let fitness = lisa.calculate_fitness();
+        for start in 0..2 {
+            if let Some((mut better, f)) = optimise_window(&lisa, fitness, start, &cfg) {
+                assert!(f < fitness);
+                assert_eq!(f, better.calculate_fitness());
+                assert!(same_structure(&better, &before));
+            }
+        }
+
+        let report = optimise(&mut lisa, &cfg);
+        assert!(report.fitness_after <= report.fitness_before);
+        assert_eq!(report.fitness_after, lisa.calculate_fitness());
+        assert!(same_structure(&lisa, &before));
+        for s in lisa.shapes.shapes.iter() {
+            for (v, (lo, hi)) in s.params().into_iter().zip(s.param_bounds(0.)) {
+                assert!(v >= lo && v <= hi);
+            }
+        }
+    }
+}
@@ -0,0 +1,176 @@
+// Genome distances, and the niching schemes built on them that stop a
+// small population collapsing into copies of one strain.
+
+use lisa::Lisa;
+use canvas::Canvas;
+use shapelist::ShapeList;
+
+/// Rendered canvases are compared on a grid this many samples wide
+const SAMPLES: usize = 32;
+
+/// How the distance between two genomes is measured
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Distance {
+    /// Match shapes of the same kind pairwise, by geometry and colour
+    Shapes,
+    /// Compare the rendered images at low resolution
+    Canvas
+}
+
+/// How the population is kept diverse
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Niching {
+    None,
+    /// Scale fitness up by the number of neighbours within this radius
+    Sharing(f64),
+    /// Each child competes only with the parent it most resembles
+    Crowding
+}
+
+impl Distance {
+    pub fn parse(s: &str) -> Option<Distance> {
+        match s {
+            "shapes" => Some(Distance::Shapes),
+            "canvas" => Some(Distance::Canvas),
+            _ => None
+        }
+    }
+
+    /// From 0 for identical genomes to 1 for nothing in common
+    pub fn between(&self, a: &Lisa, b: &Lisa) -> f64 {
+        match *self {
+            Distance::Shapes => shape_distance(&a.shapes, &b.shapes),
+            Distance::Canvas => canvas_distance(&a.ctx.cache.canvas_for(&a.shapes),
+                                                &b.ctx.cache.canvas_for(&b.shapes))
+        }
+    }
+
+    /// Mean distance over every pair
+    pub fn diversity(&self, population: &[&Lisa]) -> f64 {
+        let n = population.len();
+        if n < 2 {
+            return 0.;
+        }
+        let mut total = 0.;
+        for i in 0..n {
+            for j in (i + 1)..n {
+                total += self.between(population[i], population[j]);
+            }
+        }
+        return total / (n * (n - 1) / 2) as f64;
+    }
+}
+
+/// Each shape of `a` is matched to the nearest unmatched shape of the
+/// same kind in `b`. Unmatched shapes, on either side, count as 1.
+pub fn shape_distance(a: &ShapeList, b: &ShapeList) -> f64 {
+    let mut used = vec![false; b.len()];
+    let mut total = a.background.distance(&b.background) as f64;
+    for s in a.shapes.iter() {
+        let mut nearest: Option<(usize, f32)> = None;
+        for (j, t) in b.shapes.iter().enumerate() {
+            if used[j] {
+                continue;
+            }
+            if let Some(d) = s.distance(t) {
+                if nearest.map_or(true, |n| d < n.1) {
+                    nearest = Some((j, d));
+                }
+            }
+        }
+        match nearest {
+            Some((j, d)) => {
+                used[j] = true;
+                total += d.min(1.) as f64;
+            },
+            None => total += 1.
+        }
+    }
+    let matched = used.iter().filter(|&&u| u).count();
+    let unmatched_b = b.len() - matched;
+    let unmatched_a = a.len() - matched;
+    total += unmatched_b as f64;
+    // Every term above is at most 1, the background included
+    return total / (matched + unmatched_a + unmatched_b + 1) as f64;
+}
+
+/// Mean colour difference over a coarse grid
+pub fn canvas_distance(a: &Canvas, b: &Canvas) -> f64 {
+    let mut total = 0.;
+    for i in 0..SAMPLES {
+        for j in 0..SAMPLES {
+            let x = (i * a.width / SAMPLES) as i32;
+            let y = (j * a.height / SAMPLES) as i32;
+            total += a.pixel_at(x, y).distance(&b.pixel_at(x, y)) as f64;
+        }
+    }
+    return total / (SAMPLES * SAMPLES) as f64;
+}
+
+/// Fitness scaled by each member's niche count, so crowded strains look
+/// worse than lone ones (fitness is minimised)
+pub fn shared_fitness(population: &[&Lisa], fitness: &[f64], distance: Distance, radius: f64) -> Vec<f64> {
+    let n = population.len();
+    return (0..n).map(|i| {
+        let mut niche = 0.;
+        for j in 0..n {
+            let d = if i == j { 0. } else { distance.between(population[i], population[j]) };
+            if d < radius {
+                niche += 1. - d / radius;
+            }
+        }
+        fitness[i] * niche.max(1.)
+    }).collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::shape_distance;
+    use shapelist::ShapeList;
+    use shapes::{Shape, Rect};
+    use color::Color;
+
+    fn genome(xs: &[f32]) -> ShapeList {
+        let mut sl = ShapeList::new();
+        for &x in xs {
+            sl.shapes.push(Shape::circle(x, 0.5, 0.2, Color { r: 200., g: 100., b: 50., opacity: 0.5 }));
+        }
+        return sl;
+    }
+
+    #[test]
+    fn shape_distances(){
+        let a = genome(&[0.1, 0.5, 0.9]);
+        assert_eq!(shape_distance(&a, &a), 0.);
+        // Order doesn't matter, only which shapes there are
+        assert_eq!(shape_distance(&a, &genome(&[0.9, 0.1, 0.5])), 0.);
+
+        let near = shape_distance(&a, &genome(&[0.1, 0.5, 0.8]));
+        let far = shape_distance(&a, &genome(&[0.1]));
+        assert!(near > 0. && near < far);
+        assert!(far <= 1.);
+        assert_eq!(far, shape_distance(&genome(&[0.1]), &a));
+    }
+
+    #[test]
+    fn different_kinds_stay_in_range(){
+        let circles = genome(&[0.1, 0.5, 0.9]);
+        let mut rects = ShapeList::new();
+        for s in circles.shapes.iter() {
+            rects.shapes.push(Shape::Rect(Rect {
+                x: s.centre().0, y: 0.5, width: 0.2, height: 0.2,
+                color: s.color().clone(),
+                blend: Default::default(),
+                steps: Default::default()
+            }));
+        }
+        // Nothing can be matched, but the backgrounds agree
+        assert_eq!(shape_distance(&circles, &rects), 6. / 7.);
+        assert_eq!(shape_distance(&rects, &circles), 6. / 7.);
+
+        let mut mixed = circles.clone();
+        mixed.shapes[1] = rects.shapes[1].clone();
+        let d = shape_distance(&circles, &mixed);
+        assert!(d > 0. && d < 6. / 7.);
+    }
+}
@@ -0,0 +1,17 @@
+use lisa::Lisa;
+
+/// An optimiser that improves a genome a step at a time. Steps are a
+/// generation for population engines, or a single candidate for single
+/// genome ones.
+pub trait Engine {
+    fn step(&mut self);
+    fn finished(&self) -> bool;
+    fn best(&self) -> &Lisa;
+
+    fn run(&mut self) -> Lisa {
+        while !self.finished() {
+            self.step();
+        }
+        return self.best().clone();
+    }
+}
@@ -0,0 +1,493 @@
+// The built in population engine: (μ+λ) or (μ,λ) evolution with
+// tournament or truncation parent selection and elitism, optionally
+// with fitness sharing or crowding to keep the population diverse.
+
+use lisa::Lisa;
+use engine::Engine;
+use stagnation::{Stagnation, Stall, Restart};
+use diversity::{Distance, Niching, shared_fitness};
+use rando::{Rng, rand};
+use std::thread;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strategy {
+    /// Parents compete with their offspring for survival
+    Plus,
+    /// Only offspring survive, apart from the elite
+    Comma
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Selection {
+    /// Best of k random individuals
+    Tournament(usize),
+    /// Uniformly from the better half
+    Truncation
+}
+
+#[derive(Debug, Clone)]
+pub struct EvolutionConfig {
+    pub mu: usize,
+    pub lambda: usize,
+    pub strategy: Strategy,
+    pub selection: Selection,
+    /// Parents always carried into the next generation (comma only,
+    /// plus keeps the best anyway)
+    pub elitism: usize,
+    /// Mutations applied to each offspring
+    pub mutations: usize,
+    /// Chance that an offspring is bred from two parents before it is
+    /// mutated
+    pub crossover: f32,
+    /// Stop after this many generations, or run forever
+    pub generations: Option<u64>,
+    /// Threads to score offspring on
+    pub threads: usize,
+    /// When to restart, what a restart does is the context's `restart`
+    pub stagnation: Stagnation,
+    pub niching: Niching,
+    /// Used by niching and the diversity report
+    pub distance: Distance
+}
+
+impl Default for EvolutionConfig {
+    fn default() -> EvolutionConfig {
+        EvolutionConfig {
+            mu: 3,
+            lambda: 6,
+            strategy: Strategy::Plus,
+            selection: Selection::Tournament(2),
+            elitism: 1,
+            mutations: 1,
+            crossover: 0.,
+            generations: None,
+            threads: 1,
+            stagnation: Stagnation::default(),
+            niching: Niching::None,
+            distance: Distance::Shapes
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub lisa: Lisa,
+    pub fitness: f64
+}
+
+impl Member {
+    pub fn new(mut lisa: Lisa) -> Member {
+        let fitness = lisa.calculate_fitness();
+        return Member { lisa: lisa, fitness: fitness };
+    }
+}
+
+pub struct Evolution {
+    pub cfg: EvolutionConfig,
+    /// Sorted, best first
+    pub population: Vec<Member>,
+    pub generation: u64,
+    best: Lisa,
+    best_fitness: f64,
+    stall: Stall
+}
+
+/// Population indices of a child's parent, and its second parent if it
+/// was bred
+type Parents = (usize, Option<usize>);
+
+/// Generations between status lines
+const REPORT: u64 = 100;
+
+fn sort(population: &mut Vec<Member>) {
+    population.sort_by(|a, b| a.fitness.partial_cmp(&b.fitness).unwrap());
+}
+
+/// Reorder a sorted pool by shared fitness, keeping the fittest first
+fn share(pool: Vec<Member>, distance: Distance, radius: f64) -> Vec<Member> {
+    let shared = {
+        let lisas: Vec<&Lisa> = pool.iter().map(|m| &m.lisa).collect();
+        let fitness: Vec<f64> = pool.iter().map(|m| m.fitness).collect();
+        shared_fitness(&lisas, &fitness, distance, radius)
+    };
+    let mut order: Vec<usize> = (1..pool.len()).collect();
+    order.sort_by(|&a, &b| shared[a].partial_cmp(&shared[b]).unwrap());
+    order.insert(0, 0);
+    let mut pool: Vec<Option<Member>> = pool.into_iter().map(Some).collect();
+    return order.into_iter().map(|i| pool[i].take().unwrap()).collect();
+}
+
+impl Evolution {
+    pub fn new(cfg: EvolutionConfig, initial: Vec<Lisa>) -> Evolution {
+        assert!(!initial.is_empty(), "The native engine needs a population of at least one");
+        let mut population: Vec<Member> = initial.into_iter().map(Member::new).collect();
+        sort(&mut population);
+        population.truncate(cfg.mu.max(1));
+        for m in population.iter_mut() {
+            m.lisa.accept();
+        }
+        let best = population[0].lisa.clone();
+        let best_fitness = population[0].fitness;
+        return Evolution {
+            stall: Stall::new(cfg.stagnation.clone()),
+            cfg: cfg,
+            population: population,
+            generation: 0,
+            best: best,
+            best_fitness: best_fitness
+        };
+    }
+
+    pub fn best_fitness(&self) -> f64 {
+        return self.best_fitness;
+    }
+
+    /// Replace the worst members with copies of `migrants`, moved into
+    /// this population's context
+    pub fn immigrate(&mut self, migrants: &[Lisa]) {
+        let ctx = self.best.ctx.clone();
+        for m in migrants {
+            let mut lisa = m.clone();
+            lisa.ctx = ctx.clone();
+            let member = Member::new(lisa);
+            let last = self.population.len() - 1;
+            if member.fitness < self.population[last].fitness {
+                self.population[last] = member;
+                sort(&mut self.population);
+            }
+        }
+    }
+
+    /// Start the population over, keeping the best found so far
+    fn restart(&mut self) {
+        let ctx = self.best.ctx.clone();
+        let restart = ctx.restart;
+        match restart {
+            Restart::Reseed(fraction) => {
+                let n = (self.population.len() as f32 * fraction).ceil() as usize;
+                for m in self.population.iter_mut().rev().take(n) {
+                    m.lisa = Lisa::new(ctx.clone());
+                    m.lisa.mutate();
+                }
+            },
+            _ => {
+                for m in self.population.iter_mut() {
+                    if restart.from_best() {
+                        m.lisa = self.best.clone();
+                    }
+                    m.lisa.reset();
+                    if let Restart::Truncate(_) = restart {
+                        // Otherwise every member is the same clone
+                        m.lisa.mutate();
+                    }
+                }
+            }
+        }
+        evaluate(&mut self.population, self.cfg.threads);
+        sort(&mut self.population);
+        println!("# Restart {} at generation {}: {:?}, now {:.1} (best {:.1})",
+                 self.stall.restarts, self.generation, restart,
+                 self.population[0].fitness, self.best_fitness);
+    }
+
+    /// Plus or comma survivor selection, by shared fitness if sharing
+    fn survive(&mut self, mut children: Vec<Member>) {
+        let mut next = match self.cfg.strategy {
+            Strategy::Plus => {
+                let mut all = self.population.clone();
+                all.append(&mut children);
+                all
+            },
+            Strategy::Comma => {
+                let elite = self.cfg.elitism.min(self.population.len());
+                let mut all: Vec<Member> = self.population[..elite].to_vec();
+                all.append(&mut children);
+                all
+            }
+        };
+        sort(&mut next);
+        if let Niching::Sharing(radius) = self.cfg.niching {
+            next = share(next, self.cfg.distance, radius);
+        }
+        next.truncate(self.cfg.mu.max(1));
+        // Parents were credited when they first survived
+        for m in next.iter_mut() {
+            m.lisa.accept();
+        }
+        self.population = next;
+    }
+
+    /// Deterministic crowding: each child competes with whichever of its
+    /// parents it most resembles, and replaces it if fitter
+    fn crowd(&mut self, children: Vec<Member>, parents: Vec<Parents>) {
+        for (mut child, (p, q)) in children.into_iter().zip(parents) {
+            let rival = match q {
+                Some(q) if self.cfg.distance.between(&child.lisa, &self.population[q].lisa) <
+                           self.cfg.distance.between(&child.lisa, &self.population[p].lisa) => q,
+                _ => p
+            };
+            if child.fitness < self.population[rival].fitness {
+                child.lisa.accept();
+                self.population[rival] = child;
+            }
+        }
+        sort(&mut self.population);
+    }
+
+    /// Mean distance between members
+    pub fn diversity(&self) -> f64 {
+        let lisas: Vec<&Lisa> = self.population.iter().map(|m| &m.lisa).collect();
+        return self.cfg.distance.diversity(&lisas);
+    }
+
+    fn select(&self, rng: &mut Rng) -> usize {
+        let n = self.population.len();
+        match self.cfg.selection {
+            Selection::Tournament(k) => {
+                let mut winner = (rand(rng) * n as f32) as usize;
+                for _ in 1..k {
+                    // Sorted, so the lower index is the fitter
+                    winner = winner.min((rand(rng) * n as f32) as usize);
+                }
+                return winner;
+            },
+            Selection::Truncation => {
+                let top = n.div_ceil(2);
+                return (rand(rng) * top as f32) as usize;
+            }
+        }
+    }
+
+    /// Mutants of selected parents, some bred with a second parent
+    /// first, along with the indices of their parents. Mutation runs in
+    /// order on this thread so the random stream is the same however
+    /// many threads score them.
+    fn offspring(&mut self) -> (Vec<Member>, Vec<Parents>) {
+        let ctx = self.best.ctx.clone();
+        let mut children = Vec::with_capacity(self.cfg.lambda);
+        let mut parents = Vec::with_capacity(self.cfg.lambda);
+        for _ in 0..self.cfg.lambda {
+            let (mut child, from) = {
+                let mut rng = ctx.rng.lock().unwrap();
+                let parent = self.select(&mut rng);
+                let mut child = self.population[parent].lisa.clone();
+                let mut second = None;
+                if self.population.len() > 1 && rand(&mut rng) < self.cfg.crossover {
+                    let mut other = self.select(&mut rng);
+                    if other == parent {
+                        other = (parent + 1) % self.population.len();
+                    }
+                    child.breed(&self.population[other].lisa.shapes, &mut rng);
+                    second = Some(other);
+                }
+                (child, (parent, second))
+            };
+            for _ in 0..self.cfg.mutations.max(1) {
+                child.mutate();
+            }
+            children.push(Member { lisa: child, fitness: 0. });
+            parents.push(from);
+        }
+        evaluate(&mut children, self.cfg.threads);
+        return (children, parents);
+    }
+}
+
+/// Score members in parallel over `threads` threads, sharing the
+/// context's canvas cache
+pub fn evaluate(members: &mut [Member], threads: usize) {
+    if threads <= 1 || members.len() < 2 {
+        for m in members.iter_mut() {
+            m.fitness = m.lisa.calculate_fitness();
+        }
+        return;
+    }
+    let chunk = members.len().div_ceil(threads);
+    thread::scope(|s| {
+        for part in members.chunks_mut(chunk) {
+            s.spawn(move || {
+                for m in part.iter_mut() {
+                    m.fitness = m.lisa.calculate_fitness();
+                }
+            });
+        }
+    });
+}
+
+impl Engine for Evolution {
+    fn step(&mut self) {
+        let (children, parents) = self.offspring();
+        if self.cfg.niching == Niching::Crowding {
+            self.crowd(children, parents);
+        } else {
+            self.survive(children);
+        }
+        self.generation += 1;
+
+        if self.population[0].fitness < self.best_fitness {
+            self.best_fitness = self.population[0].fitness;
+            self.best = self.population[0].lisa.clone();
+            self.best.new_fittest_found();
+            self.stall.improved();
+        } else if self.stall.stalled() {
+            self.restart();
+        }
+        if self.generation % REPORT == 0 {
+            println!("# Generation {}, best {:.1}, diversity {:.3}",
+                     self.generation, self.best_fitness, self.diversity());
+        }
+    }
+
+    fn finished(&self) -> bool {
+        match self.cfg.generations {
+            Some(n) => self.generation >= n,
+            None => self.best_fitness <= 0.
+        }
+    }
+
+    fn best(&self) -> &Lisa {
+        return &self.best;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use context::Context;
+    use shapes::Shape;
+    use shapelist::ShapeList;
+    use color::Color;
+    use std::sync::Arc;
+
+    /// A one circle genome at `x`, with a made up fitness
+    fn member(ctx: &Arc<Context>, x: f32, fitness: f64) -> Member {
+        let mut lisa = Lisa::new(ctx.clone());
+        lisa.shapes.shapes.push(Shape::circle(x, 0.5, 0.1, Color::black()));
+        return Member { lisa: lisa, fitness: fitness };
+    }
+
+    fn evolution(cfg: EvolutionConfig, ctx: Context, fitness: &[f64]) -> Evolution {
+        let ctx = Arc::new(ctx);
+        let n = fitness.len() as f32;
+        let population: Vec<Member> = fitness.iter().enumerate()
+            .map(|(i, &f)| member(&ctx, i as f32 / n, f)).collect();
+        return Evolution {
+            stall: Stall::new(cfg.stagnation.clone()),
+            cfg: cfg,
+            best: population[0].lisa.clone(),
+            best_fitness: population[0].fitness,
+            population: population,
+            generation: 0
+        };
+    }
+
+    fn fitness(e: &Evolution) -> Vec<f64> {
+        return e.population.iter().map(|m| m.fitness).collect();
+    }
+
+    fn children(e: &Evolution, fitness: &[f64]) -> Vec<Member> {
+        let ctx = e.best.ctx.clone();
+        return fitness.iter().map(|&f| member(&ctx, 0.5, f)).collect();
+    }
+
+    #[test]
+    fn survivors(){
+        let mut cfg = EvolutionConfig::default();
+        let mut e = evolution(cfg.clone(), Context::sample(), &[1., 3., 5.]);
+        let kids = children(&e, &[2., 4., 0.5]);
+        e.survive(kids);
+        assert_eq!(fitness(&e), vec![0.5, 1., 2.]);
+
+        // Comma: the elite, then only children
+        cfg.strategy = Strategy::Comma;
+        let mut e = evolution(cfg.clone(), Context::sample(), &[1., 3., 5.]);
+        let kids = children(&e, &[2., 4., 6.]);
+        e.survive(kids);
+        assert_eq!(fitness(&e), vec![1., 2., 4.]);
+        cfg.elitism = 0;
+        let mut e = evolution(cfg, Context::sample(), &[1., 3., 5.]);
+        let kids = children(&e, &[2., 4., 6.]);
+        e.survive(kids);
+        assert_eq!(fitness(&e), vec![2., 4., 6.]);
+    }
+
+    #[test]
+    fn selection(){
+        let mut cfg = EvolutionConfig::default();
+        let mut rng = Rng::new(1);
+        let fit = [1., 2., 3., 4., 5., 6.];
+        let mean = |e: &Evolution, rng: &mut Rng| (0..1000).map(|_| e.select(rng)).sum::<usize>() as f64 / 1000.;
+
+        cfg.selection = Selection::Tournament(1);
+        let uniform = evolution(cfg.clone(), Context::sample(), &fit);
+        cfg.selection = Selection::Tournament(3);
+        let pressured = evolution(cfg.clone(), Context::sample(), &fit);
+        assert!(mean(&pressured, &mut rng) < mean(&uniform, &mut rng) - 1.);
+        assert!((0..6).all(|i| (0..1000).any(|_| uniform.select(&mut rng) == i)));
+
+        cfg.selection = Selection::Truncation;
+        let truncated = evolution(cfg, Context::sample(), &fit);
+        assert!((0..1000).all(|_| truncated.select(&mut rng) < 3));
+    }
+
+    #[test]
+    fn restarts_keep_the_best(){
+        let mut ctx = Context::sample();
+        ctx.restart = Restart::Wipe;
+        let mut e = evolution(EvolutionConfig::default(), ctx, &[1., 2., 3., 4.]);
+        e.restart();
+        assert!(e.population.iter().all(|m| m.lisa.shapes.len() == 0));
+        assert_eq!(e.best_fitness, 1.);
+        assert_eq!(e.best.shapes.len(), 1);
+
+        let mut ctx = Context::sample();
+        ctx.restart = Restart::Reseed(0.5);
+        let mut e = evolution(EvolutionConfig::default(), ctx, &[1., 2., 3., 4.]);
+        let before: Vec<ShapeList> = e.population.iter().map(|m| m.lisa.shapes.clone()).collect();
+        e.restart();
+        let kept = |sl: &ShapeList| e.population.iter().any(|m| m.lisa.shapes == *sl);
+        assert!(kept(&before[0]) && kept(&before[1]));
+        assert!(!kept(&before[2]) && !kept(&before[3]));
+        // Rescored and sorted
+        assert!(e.population.windows(2).all(|w| w[0].fitness <= w[1].fitness));
+    }
+
+    #[test]
+    fn crowding_replaces_the_nearer_parent(){
+        let mut cfg = EvolutionConfig::default();
+        cfg.niching = Niching::Crowding;
+        let mut e = evolution(cfg, Context::sample(), &[1., 3.]);
+        let ctx = e.best.ctx.clone();
+        // Bred from both, but it looks like the second
+        e.crowd(vec![member(&ctx, 0.45, 2.)], vec![(0, Some(1))]);
+        assert_eq!(fitness(&e), vec![1., 2.]);
+        // Not fitter than the parent it resembles
+        e.crowd(vec![member(&ctx, 0.05, 1.5)], vec![(0, None)]);
+        assert_eq!(fitness(&e), vec![1., 2.]);
+        e.crowd(vec![member(&ctx, 0.05, 0.5)], vec![(1, Some(0))]);
+        assert_eq!(fitness(&e), vec![0.5, 2.]);
+    }
+
+    #[test]
+    fn finishing(){
+        let mut cfg = EvolutionConfig::default();
+        cfg.generations = Some(5);
+        let mut e = evolution(cfg, Context::sample(), &[1.]);
+        e.generation = 4;
+        assert!(!e.finished());
+        e.generation = 5;
+        assert!(e.finished());
+
+        let mut e = evolution(EvolutionConfig::default(), Context::sample(), &[1.]);
+        e.generation = 1000;
+        assert!(!e.finished());
+        e.best_fitness = 0.;
+        assert!(e.finished());
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one")]
+    fn empty_population(){
+        Evolution::new(EvolutionConfig::default(), Vec::new());
+    }
+}
@@ -0,0 +1,211 @@
+// Greedy construction, in the manner of fogleman/primitive: shapes are
+// added one at a time on top of a fixed base canvas and never touched
+// again. Each step samples many candidates for the top layer, hill
+// climbs the best few and commits the winner if it helps.
+
+use lisa::Lisa;
+use canvas::Canvas;
+use shapes::Shape;
+use shapelist::ShapeList;
+use context::Context;
+use engine::Engine;
+use guide;
+use rando::Rng;
+use std::thread;
+
+#[derive(Debug, Clone)]
+pub struct GreedyConfig {
+    /// Stop once the genome has this many shapes
+    pub shapes: usize,
+    /// Random candidates sampled for each new shape
+    pub candidates: usize,
+    /// How many of the best candidates are hill climbed
+    pub climbers: usize,
+    /// Mutations tried on each climber
+    pub climb: usize,
+    /// Threads to score candidates on
+    pub threads: usize,
+    /// Give up after this many steps in a row add nothing
+    pub patience: usize
+}
+
+impl Default for GreedyConfig {
+    fn default() -> GreedyConfig {
+        GreedyConfig {
+            shapes: 200,
+            candidates: 200,
+            climbers: 3,
+            climb: 100,
+            threads: 1,
+            patience: 10
+        }
+    }
+}
+
+pub struct Greedy {
+    cfg: GreedyConfig,
+    lisa: Lisa,
+    fitness: f64,
+    /// Steps in a row where no candidate improved the image
+    failures: usize
+}
+
+/// Fitness of `shape` drawn over `base`
+fn score(ctx: &Context, base: &Canvas, shape: &Shape) -> f64 {
+    let mut canv = base.clone();
+    shape.draw_onto(&mut canv);
+    return Lisa::score(ctx, &canv);
+}
+
+/// Score every candidate over `threads` threads
+fn score_all(ctx: &Context, base: &Canvas, shapes: &[Shape], threads: usize) -> Vec<f64> {
+    if threads <= 1 || shapes.len() < 2 {
+        return shapes.iter().map(|s| score(ctx, base, s)).collect();
+    }
+    let chunk = shapes.len().div_ceil(threads);
+    return thread::scope(|s| {
+        let handles: Vec<_> = shapes.chunks(chunk).map(|part| {
+            s.spawn(move || part.iter().map(|sh| score(ctx, base, sh)).collect::<Vec<f64>>())
+        }).collect();
+        handles.into_iter().flat_map(|h| h.join().expect("scoring panicked")).collect()
+    });
+}
+
+/// Mutate `shape` `n` times, keeping only the changes that improve it
+fn climb(ctx: &Context, base: &Canvas, mut shape: Shape, mut fitness: f64,
+         n: usize, rng: &mut Rng) -> (Shape, f64) {
+    for _ in 0..n {
+        let mut candidate = shape.clone();
+        candidate.mutate(ctx, rng);
+        let f = score(ctx, base, &candidate);
+        if f < fitness {
+            shape = candidate;
+            fitness = f;
+        }
+    }
+    return (shape, fitness);
+}
+
+impl Greedy {
+    pub fn new(cfg: GreedyConfig, mut lisa: Lisa) -> Greedy {
+        let fitness = lisa.calculate_fitness();
+        lisa.cache_shapes();
+        return Greedy {
+            cfg: cfg,
+            lisa: lisa,
+            fitness: fitness,
+            failures: 0
+        };
+    }
+
+    /// Half uniformly random, half placed on the worst of the error map
+    fn candidates(&self, base: &Canvas, rng: &mut Rng) -> Vec<Shape> {
+        let ctx = &self.lisa.ctx;
+        let errors = guide::error_map(base, &ctx.image);
+        return (0..self.cfg.candidates.max(1)).map(|i| {
+            if i % 2 == 0 {
+                Shape::random(ctx, rng)
+            } else {
+                ShapeList::shape_for_errors(ctx, &errors, rng)
+            }
+        }).collect();
+    }
+}
+
+impl Engine for Greedy {
+    /// Try to add one shape
+    fn step(&mut self) {
+        let ctx = self.lisa.ctx.clone();
+        let base = ctx.cache.canvas_for(&self.lisa.shapes);
+        let mut rng = ctx.rng.lock().unwrap();
+
+        let shapes = self.candidates(&base, &mut rng);
+        let scores = score_all(&ctx, &base, &shapes, self.cfg.threads);
+        let mut order: Vec<usize> = (0..shapes.len()).collect();
+        order.sort_by(|&a, &b| scores[a].partial_cmp(&scores[b]).unwrap());
+
+        let mut winner: Option<(Shape, f64)> = None;
+        for &i in order.iter().take(self.cfg.climbers.max(1)) {
+            let (shape, f) = climb(&ctx, &base, shapes[i].clone(), scores[i], self.cfg.climb, &mut rng);
+            if winner.as_ref().map_or(true, |w| f < w.1) {
+                winner = Some((shape, f));
+            }
+        }
+        drop(rng);
+
+        match winner {
+            Some((shape, f)) if f < self.fitness => {
+                self.lisa.shapes.shapes.push(shape);
+                self.lisa.cache_shapes();
+                self.fitness = f;
+                self.failures = 0;
+                println!("# Greedy shape {} of {}, fitness {:.1}",
+                         self.lisa.shapes.len(), self.cfg.shapes, f);
+                self.lisa.new_fittest_found();
+            },
+            _ => {
+                self.failures += 1;
+            }
+        }
+    }
+
+    /// The budget is spent, or the last few steps found nothing to add
+    fn finished(&self) -> bool {
+        return self.lisa.shapes.len() >= self.cfg.shapes || self.failures >= self.cfg.patience;
+    }
+
+    fn best(&self) -> &Lisa {
+        return &self.lisa;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    fn greedy() -> Greedy {
+        let mut ctx = Context::sample();
+        ctx.best_fitness = Arc::new(Mutex::new(::std::f64::NEG_INFINITY));
+        let cfg = GreedyConfig { shapes: 4, candidates: 20, climbers: 2, climb: 10, ..GreedyConfig::default() };
+        return Greedy::new(cfg, Lisa::new(Arc::new(ctx)));
+    }
+
+    #[test]
+    fn only_ever_adds_one_better_shape(){
+        let mut g = greedy();
+        let mut added = 0;
+        for _ in 0..20 {
+            if g.finished() {
+                break;
+            }
+            let (before, fitness) = (g.lisa.shapes.clone(), g.fitness);
+            g.step();
+            if g.lisa.shapes.len() == before.len() {
+                assert_eq!(g.lisa.shapes, before);
+                assert_eq!(g.fitness, fitness);
+                continue;
+            }
+            assert_eq!(g.lisa.shapes.len(), before.len() + 1);
+            assert_eq!(g.lisa.shapes.shapes[..before.len()], before.shapes[..]);
+            assert!(g.fitness < fitness);
+            assert_eq!(g.fitness, g.lisa.calculate_fitness());
+            added += 1;
+        }
+        assert!(added > 0);
+    }
+
+    #[test]
+    fn gives_up_after_patience(){
+        let mut g = greedy();
+        g.cfg.patience = 3;
+        // Nothing can beat a perfect score
+        g.fitness = 0.;
+        for _ in 0..3 {
+            assert!(!g.finished());
+            g.step();
+        }
+        assert!(g.finished());
+        assert_eq!(g.lisa.shapes.len(), 0);
+    }
+}
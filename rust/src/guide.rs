@@ -0,0 +1,76 @@
+// Helpers for placing new shapes where the current image is worst.
+
+use canvas::Canvas;
+use rando::{Rng, rand};
+
+/// Squared error of each pixel (summed over channels) against the target
+pub fn error_map(canv: &Canvas, target: &Canvas) -> Vec<f32> {
+    let mut errors = Vec::with_capacity(canv.width * canv.height);
+    for y in 0..canv.height as i32 {
+        for x in 0..canv.width as i32 {
+            errors.push(canv.pixel_diff_sq_with(target, x, y) as f32);
+        }
+    }
+    return errors;
+}
+
+/// Pick a pixel with probability proportional to its error
+pub fn sample(errors: &[f32], width: usize, rng: &mut Rng) -> (usize, usize) {
+    let total: f64 = errors.iter().map(|e| *e as f64).sum();
+    let mut target = rand(rng) as f64 * total;
+    for (i, e) in errors.iter().enumerate() {
+        target -= *e as f64;
+        if target <= 0. {
+            return (i % width, i / width);
+        }
+    }
+    let i = (rand(rng) * errors.len() as f32) as usize;
+    return (i % width, i / width);
+}
+
+fn window_mean(errors: &[f32], width: usize, height: usize, x: usize, y: usize, r: usize) -> f32 {
+    let xmin = x.saturating_sub(r);
+    let ymin = y.saturating_sub(r);
+    let xmax = (x + r).min(width - 1);
+    let ymax = (y + r).min(height - 1);
+    let mut total = 0.;
+    for py in ymin .. ymax + 1 {
+        for px in xmin .. xmax + 1 {
+            total += errors[py * width + px];
+        }
+    }
+    return total / ((xmax - xmin + 1) * (ymax - ymin + 1)) as f32;
+}
+
+/// Radius in pixels of the high error region around (x, y): the window
+/// keeps doubling while its mean error stays above half that of the
+/// immediate neighbourhood.
+pub fn blob_radius(errors: &[f32], width: usize, height: usize, x: usize, y: usize) -> usize {
+    let mut r = 2;
+    let core = window_mean(errors, width, height, x, y, r);
+    while r * 2 < width.max(height) / 2 {
+        if window_mean(errors, width, height, x, y, r * 2) < core * 0.5 {
+            break;
+        }
+        r *= 2;
+    }
+    return r;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_the_error() {
+        let mut errors = vec![0.; 64 * 64];
+        for y in 10..30 {
+            for x in 40..60 {
+                errors[y * 64 + x] = 1.;
+            }
+        }
+        let (x, y) = sample(&errors, 64, &mut Rng::new(1));
+        assert!(x >= 40 && x < 60 && y >= 10 && y < 30);
+        assert_eq!(blob_radius(&errors, 64, 64, 50, 20), 8);
+    }
+}
@@ -0,0 +1,150 @@
+// Island model: independent populations on their own threads, each with
+// its own context (and so its own canvas cache), swapping their best
+// genomes every few generations.
+
+use lisa::Lisa;
+use context::Context;
+use engine::Engine;
+use evolution::{Evolution, EvolutionConfig};
+use rando::{Rng, rand};
+use std::sync::Arc;
+use std::thread;
+
+/// Which islands send migrants to which
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Topology {
+    /// Each island receives from the one before it
+    Ring,
+    /// Each island receives from every other
+    Full,
+    /// Each island receives from one other, picked afresh every time
+    Random
+}
+
+impl Topology {
+    pub fn parse(s: &str) -> Option<Topology> {
+        match s {
+            "ring" => Some(Topology::Ring),
+            "full" => Some(Topology::Full),
+            "random" => Some(Topology::Random),
+            _ => None
+        }
+    }
+
+    /// The islands that send to island `i` of `n`
+    pub fn sources(&self, i: usize, n: usize, rng: &mut Rng) -> Vec<usize> {
+        if n < 2 {
+            return Vec::new();
+        }
+        match *self {
+            Topology::Ring => vec![(i + n - 1) % n],
+            Topology::Full => (0..n).filter(|&j| j != i).collect(),
+            Topology::Random => {
+                let j = (rand(rng) * (n - 1) as f32) as usize;
+                vec![if j >= i { j + 1 } else { j }]
+            }
+        }
+    }
+}
+
+pub struct Islands {
+    islands: Vec<Evolution>,
+    topology: Topology,
+    /// Generations between migrations
+    interval: u64,
+    generations: Option<u64>,
+    generation: u64,
+    ctx: Arc<Context>
+}
+
+impl Islands {
+    /// `n` islands, each starting from copies of `initial`
+    pub fn new(cfg: EvolutionConfig, ctx: Arc<Context>, initial: Vec<Lisa>,
+               n: usize, topology: Topology, interval: u64) -> Islands {
+        let islands = (0..n).map(|i| {
+            let ictx = Arc::new(ctx.island(i));
+            let pop = initial.iter().map(|l| {
+                let mut l = l.clone();
+                l.ctx = ictx.clone();
+                l
+            }).collect();
+            Evolution::new(cfg.clone(), pop)
+        }).collect();
+        return Islands {
+            generations: cfg.generations,
+            islands: islands,
+            topology: topology,
+            interval: interval.max(1),
+            generation: 0,
+            ctx: ctx
+        };
+    }
+
+    fn migrate(&mut self) {
+        let bests: Vec<Lisa> = self.islands.iter().map(|e| e.best().clone()).collect();
+        let n = self.islands.len();
+        let mut rng = self.ctx.rng.lock().unwrap();
+        for i in 0..n {
+            let migrants: Vec<Lisa> = self.topology.sources(i, n, &mut rng)
+                .into_iter().map(|j| bests[j].clone()).collect();
+            self.islands[i].immigrate(&migrants);
+        }
+    }
+}
+
+impl Engine for Islands {
+    /// Run every island for one migration interval in parallel, then
+    /// migrate
+    fn step(&mut self) {
+        let interval = self.interval;
+        let handles: Vec<_> = self.islands.drain(..).map(|mut e| {
+            thread::spawn(move || {
+                for _ in 0..interval {
+                    e.step();
+                }
+                e
+            })
+        }).collect();
+        self.islands = handles.into_iter().map(|h| h.join().expect("island panicked")).collect();
+        self.generation += interval;
+
+        let bests: Vec<String> = self.islands.iter().map(|e| format!("{:.0}", e.best_fitness())).collect();
+        println!("# Generation {}, island bests: {}", self.generation, bests.join(" "));
+        self.migrate();
+    }
+
+    fn finished(&self) -> bool {
+        match self.generations {
+            Some(n) => self.generation >= n,
+            None => false
+        }
+    }
+
+    fn best(&self) -> &Lisa {
+        let mut best = &self.islands[0];
+        for e in self.islands.iter() {
+            if e.best_fitness() < best.best_fitness() {
+                best = e;
+            }
+        }
+        return best.best();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Topology;
+    use rando::Rng;
+
+    #[test]
+    fn topologies(){
+        let mut rng = Rng::new(1);
+        assert_eq!(Topology::Ring.sources(0, 4, &mut rng), vec![3]);
+        assert_eq!(Topology::Full.sources(1, 3, &mut rng), vec![0, 2]);
+        for _ in 0..20 {
+            let s = Topology::Random.sources(2, 4, &mut rng);
+            assert!(s.len() == 1 && s[0] != 2 && s[0] < 4);
+        }
+        assert!(Topology::Ring.sources(0, 1, &mut rng).is_empty());
+    }
+}
@@ -0,0 +1,194 @@
+extern crate serde_json;
+
+use shapes::Shape;
+use shapelist::ShapeList;
+use color::Color;
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use std::io::{BufRead, BufReader, Write};
+
+/// One edit between consecutive bests. Anything that isn't a single
+/// add, remove, swap or change (crossover, merge, split, moves) is
+/// recorded as a splice of the differing run of shapes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Diff {
+    Added { index: usize, shape: Shape },
+    Removed { index: usize },
+    Swapped { a: usize, b: usize },
+    Changed { index: usize, shape: Shape },
+    Splice { index: usize, remove: usize, shapes: Vec<Shape> },
+    Background { color: Color }
+}
+
+/// A line of the lineage file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub mutations: u64,
+    pub fitness: f64,
+    pub diffs: Vec<Diff>
+}
+
+pub fn diff(before: &ShapeList, after: &ShapeList) -> Vec<Diff> {
+    let mut out = Vec::new();
+    if before.background != after.background {
+        out.push(Diff::Background { color: after.background.clone() });
+    }
+
+    let a = &before.shapes;
+    let b = &after.shapes;
+    let mut start = 0;
+    while start < a.len() && start < b.len() && a[start] == b[start] {
+        start += 1;
+    }
+    let mut end = 0;
+    while end < a.len() - start && end < b.len() - start &&
+            a[a.len() - 1 - end] == b[b.len() - 1 - end] {
+        end += 1;
+    }
+    let old = &a[start..a.len() - end];
+    let new = &b[start..b.len() - end];
+
+    if old.len() == 0 && new.len() == 0 {
+        return out;
+    }
+    if old.len() == 0 && new.len() == 1 {
+        out.push(Diff::Added { index: start, shape: new[0].clone() });
+    } else if old.len() == 1 && new.len() == 0 {
+        out.push(Diff::Removed { index: start });
+    } else if old.len() == 1 && new.len() == 1 {
+        out.push(Diff::Changed { index: start, shape: new[0].clone() });
+    } else if old.len() >= 2 && old.len() == new.len() &&
+            old[0] == new[new.len() - 1] && old[old.len() - 1] == new[0] &&
+            old[1..old.len() - 1] == new[1..new.len() - 1] {
+        out.push(Diff::Swapped { a: start, b: start + old.len() - 1 });
+    } else {
+        out.push(Diff::Splice { index: start, remove: old.len(), shapes: new.to_vec() });
+    }
+    return out;
+}
+
+pub fn apply(sl: &mut ShapeList, diffs: &[Diff]) {
+    for d in diffs {
+        match d {
+            &Diff::Added { index, ref shape } => sl.shapes.insert(index, shape.clone()),
+            &Diff::Removed { index } => { sl.shapes.remove(index); },
+            &Diff::Swapped { a, b } => sl.shapes.swap(a, b),
+            &Diff::Changed { index, ref shape } => sl.shapes[index] = shape.clone(),
+            &Diff::Splice { index, remove, ref shapes } => {
+                sl.shapes.splice(index..index + remove, shapes.iter().cloned());
+            },
+            &Diff::Background { ref color } => sl.background = color.clone()
+        }
+    }
+}
+
+/// Appends each new best to a file, as a diff to the one before. The
+/// file is only ever appended to, so resumed runs extend the history.
+#[derive(Debug)]
+pub struct Lineage {
+    file: File,
+    last: ShapeList
+}
+
+impl Lineage {
+    /// Append to the log at `path`, carrying on from the last genome
+    /// already in it
+    pub fn open(path: &str) -> Lineage {
+        let last = if Path::new(path).exists() {
+            let entries = read(path);
+            replay(&entries, entries.len(), |_, _| {})
+        } else {
+            ShapeList::new()
+        };
+        return Lineage {
+            file: OpenOptions::new().append(true).create(true).open(path)
+                .expect("couldn't open lineage file"),
+            last: last
+        };
+    }
+
+    pub fn record(&mut self, sl: &ShapeList, fitness: f64, mutations: u64) {
+        let entry = Entry {
+            mutations: mutations,
+            fitness: fitness,
+            diffs: diff(&self.last, sl)
+        };
+        let line = serde_json::to_string(&entry).expect("Serialize error");
+        writeln!(self.file, "{}", line).expect("couldn't write lineage");
+        self.last = sl.clone();
+    }
+}
+
+pub fn read(path: &str) -> Vec<Entry> {
+    let f = File::open(path).expect("couldn't open lineage file");
+    return BufReader::new(f).lines()
+        .map(|l| serde_json::from_str(&l.expect("couldn't read lineage")).expect("bad lineage entry"))
+        .collect();
+}
+
+/// Rebuild the genome after the first `steps` entries, calling `frame`
+/// with each intermediate one
+pub fn replay<F>(entries: &[Entry], steps: usize, mut frame: F) -> ShapeList
+        where F: FnMut(usize, &ShapeList) {
+    let mut sl = ShapeList::new();
+    for (i, e) in entries.iter().take(steps).enumerate() {
+        apply(&mut sl, &e.diffs);
+        frame(i, &sl);
+    }
+    return sl;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff, apply, read, replay, Diff, Lineage};
+    use shapelist::ShapeList;
+    use shapes::Shape;
+    use color::Color;
+
+    fn circle(x: f32) -> Shape {
+        return Shape::circle(x, 0.5, 0.1, Color::black());
+    }
+
+    fn list(xs: &[f32]) -> ShapeList {
+        let mut sl = ShapeList::new();
+        sl.shapes = xs.iter().map(|&x| circle(x)).collect();
+        return sl;
+    }
+
+    #[test]
+    fn diffs_round_trip(){
+        let cases = [
+            (list(&[0.1, 0.2]), list(&[0.1, 0.5, 0.2])),
+            (list(&[0.1, 0.2, 0.3]), list(&[0.1, 0.3])),
+            (list(&[0.1, 0.2, 0.3, 0.4]), list(&[0.1, 0.4, 0.3, 0.2])),
+            (list(&[0.1, 0.2, 0.3]), list(&[0.1, 0.7, 0.3])),
+            (list(&[0.1, 0.2, 0.3]), list(&[0.5, 0.6])),
+        ];
+        for &(ref a, ref b) in cases.iter() {
+            let d = diff(a, b);
+            assert_eq!(d.len(), 1);
+            let mut c = a.clone();
+            apply(&mut c, &d);
+            assert_eq!(&c, b);
+        }
+        assert_eq!(diff(&cases[2].0, &cases[2].1), vec![Diff::Swapped { a: 1, b: 3 }]);
+    }
+
+    #[test]
+    fn reopening_appends(){
+        let path = ::std::env::temp_dir().join(format!("lineage-{}.jsonl", ::std::process::id()));
+        let path = path.to_str().unwrap();
+        let _ = ::std::fs::remove_file(path);
+        Lineage::open(path).record(&list(&[0.1]), 3., 1);
+        {
+            let mut l = Lineage::open(path);
+            assert_eq!(l.last, list(&[0.1]));
+            l.record(&list(&[0.1, 0.2]), 2., 2);
+        }
+        let entries = read(path);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[1].diffs.len(), 1);
+        assert_eq!(replay(&entries, 2, |_, _| {}), list(&[0.1, 0.2]));
+        ::std::fs::remove_file(path).unwrap();
+    }
+}
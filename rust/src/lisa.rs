@@ -3,13 +3,18 @@ extern crate chrono;
 extern crate jpeg_decoder;
 
 use shapelist::{ShapeList};
-use rando::{rand};
+use shapes::Shape;
+use rando::{Rng, rand};
 
 use std::sync::Arc;
+#[cfg(feature = "darwin")]
 use darwin_rs::{Individual};
 use std::fs::File;
 use std::fmt::Write;
 use context::Context;
+use stagnation::Restart;
+use canvas::Canvas;
+use rates::Operator;
 use std;
 
 fn zero() -> u64 {
@@ -31,6 +36,19 @@ pub struct SerializedLisa {
     mutation_changes: u64,
     #[serde(default = "zero")]
     mutation_swaps: u64,
+    #[serde(default = "zero")]
+    mutation_guided: u64,
+    #[serde(default = "zero")]
+    mutation_crossovers: u64,
+    #[serde(default = "zero")]
+    mutation_inserts: u64,
+    #[serde(default = "zero")]
+    mutation_moves: u64,
+    #[serde(default = "zero")]
+    mutation_splits: u64,
+    /// Seed of the run that saved this genome
+    #[serde(default)]
+    seed: Option<u64>,
 }
 
 #[derive(Debug, Clone)]
@@ -42,19 +60,32 @@ pub struct Lisa {
     mutation_merges: u64,
     mutation_changes: u64,
     mutation_swaps: u64,
-    ctx: Arc<Context>
+    mutation_guided: u64,
+    mutation_crossovers: u64,
+    mutation_inserts: u64,
+    mutation_moves: u64,
+    mutation_splits: u64,
+    /// Operators applied since this genome was last accepted
+    applied: Vec<Operator>,
+    pub ctx: Arc<Context>
 }
 
 impl Lisa {
 	pub fn new(ctx:Arc<Context>) -> Lisa {
 		Lisa {
-			shapes: ShapeList::new(),
+			shapes: ShapeList::with_background(ctx.mean_color.clone()),
             mutations: 0,
             mutation_appends: 0,
             mutation_pops: 0,
             mutation_merges: 0,
             mutation_changes: 0,
             mutation_swaps: 0,
+            mutation_guided: 0,
+            mutation_crossovers: 0,
+            mutation_inserts: 0,
+            mutation_moves: 0,
+            mutation_splits: 0,
+            applied: Vec::new(),
             ctx: ctx
 		}
 	}
@@ -72,17 +103,25 @@ impl Lisa {
 
     pub fn make_population_from_file(count: usize, ctx: Arc<Context>, path: &str) -> Vec<Lisa> {
         let mut result = Vec::new();
-        let f = File::open(path).unwrap();
-        let saved: SerializedLisa = serde_json::from_reader(f).unwrap();
+        let saved = Lisa::load(ctx, path);
 
         for _ in 0..count {
-            result.push(Lisa::create_with(ctx.clone(), saved.clone()));
+            result.push(saved.clone());
         }
         return result;
     }
 
+    pub fn load(ctx: Arc<Context>, path: &str) -> Lisa {
+        let f = File::open(path).unwrap();
+        let saved: SerializedLisa = serde_json::from_reader(f).unwrap();
+        if let Some(seed) = saved.seed {
+            println!("# Loaded {} (saved by a run seeded with {})", path, seed);
+        }
+        return Lisa::create_with(ctx, saved);
+    }
+
     pub fn create_with(ctx:Arc<Context>, data: SerializedLisa) -> Lisa {
-        let res = Lisa {
+        let mut res = Lisa {
             shapes: data.shapes,
             mutations: data.mutations,
             mutation_appends: data.mutation_appends,
@@ -90,8 +129,25 @@ impl Lisa {
             mutation_merges: data.mutation_merges,
             mutation_changes: data.mutation_changes,
             mutation_swaps: data.mutation_swaps,
+            mutation_guided: data.mutation_guided,
+            mutation_crossovers: data.mutation_crossovers,
+            mutation_inserts: data.mutation_inserts,
+            mutation_moves: data.mutation_moves,
+            mutation_splits: data.mutation_splits,
+            applied: Vec::new(),
             ctx: ctx
         };
+        if res.ctx.opaque {
+            // Saved by a translucent run, flatten it so it can use the
+            // opaque fast path
+            for s in res.shapes.shapes.iter_mut() {
+                match *s {
+                    Shape::Triangle(ref mut t) => t.color.opacity = 1.,
+                    Shape::Rect(ref mut r) => r.color.opacity = 1.,
+                    Shape::Circle(ref mut c) => c.color.opacity = 1.
+                }
+            }
+        }
         return res;
     }
 
@@ -99,7 +155,8 @@ impl Lisa {
     pub fn svg(&self) -> String{
         return self.shapes.svg(self.ctx.width as usize,
                                self.ctx.height as usize,
-                               self.ctx.depth as usize);
+                               self.ctx.depth as usize,
+                               self.ctx.palette.as_ref());
     }
 
     fn serialize(&mut self) -> SerializedLisa {
@@ -111,51 +168,152 @@ impl Lisa {
             mutation_merges: self.mutation_merges,
             mutation_changes: self.mutation_changes,
             mutation_swaps: self.mutation_swaps,
+            mutation_guided: self.mutation_guided,
+            mutation_crossovers: self.mutation_crossovers,
+            mutation_inserts: self.mutation_inserts,
+            mutation_moves: self.mutation_moves,
+            mutation_splits: self.mutation_splits,
+            seed: Some(self.ctx.seed),
         }
     }
 
-    fn str(&mut self) -> String {
+    pub fn str(&mut self) -> String {
 		let mut out = String::new();
         let fit = self.calculate_fitness();
-		write!(&mut out, "[F:{:.0}m - {:.1} ({} shap, {} mut: {}+ {}- {}~ {}^)]",
+        let ops = &self.ctx.operators;
+        let merge_rate = ops.accepted(Operator::Merge) as f64 / ops.attempts(Operator::Merge).max(1) as f64;
+		write!(&mut out, "[F:{:.0}m - {:.1} ({} shap, {} mut: {}+ {}* {}- {}~ {}^ {}M ({:.1}% acc) {}x {}v {}<> {}/, σ:{:.4})]",
             fit / 1000_000., fit, self.shapes.len(), self.mutations,
-            self.mutation_appends, self.mutation_pops, self.mutation_changes, self.mutation_swaps
+            self.mutation_appends, self.mutation_guided, self.mutation_pops,
+            self.mutation_changes, self.mutation_swaps,
+            self.mutation_merges, 100. * merge_rate,
+            self.mutation_crossovers, self.mutation_inserts, self.mutation_moves,
+            self.mutation_splits, self.shapes.mean_step()
             ).expect("couldn't append string");
+        if self.ctx.adaptive_rates {
+            let p = self.ctx.operators.probabilities(&self.ctx.rates, true);
+            let p: Vec<String> = p.iter().map(|x| format!("{:.2}", x)).collect();
+            write!(&mut out, " p:[{}]", p.join(" ")).expect("couldn't append string");
+        }
         return out;
     }
 
+    /// Put this genome and all its prefixes in the canvas cache
+    pub fn cache_shapes(&self) {
+        self.ctx.cache.insert(&self.shapes);
+    }
+
+    /// Cache the prefixes above the first `from` shapes, which are
+    /// already cached
+    pub fn cache_from(&self, from: usize) {
+        self.ctx.cache.insert_from(&self.shapes, from);
+    }
+
+    /// Write `<name>.svg`, `<name>.json` and `<name>.png`
+    pub fn save(&mut self, name: &str) {
+        let mut svg = File::create(format!("{}.svg", name)).unwrap();
+        std::io::Write::write_all(&mut svg, self.svg().as_bytes()).expect("couldn't write");
+
+        let mut jsonfile = File::create(format!("{}.json", name)).unwrap();
+        std::io::Write::write_all(&mut jsonfile,
+                serde_json::to_string(&self.serialize()).expect("Serialize error").as_bytes()
+            ).expect("couldn't write json");
+
+        self.ctx.cache.canvas_for(&self.shapes).save(&format!("{}.png", name));
+    }
+
+    /// Replace the genome with a child of this and another genome
+    pub fn breed(&mut self, partner: &ShapeList, rng: &mut Rng) {
+        self.shapes = ShapeList::crossover(&self.shapes, partner, rng);
+        self.mutation_crossovers += 1;
+    }
+
+    /// Credit the operators applied since this genome was last
+    /// accepted, now it has replaced a parent or survived selection
+    pub fn accept(&mut self) {
+        for op in self.applied.drain(..) {
+            self.ctx.operators.accept(op);
+        }
+    }
+
 }
 
-impl Individual for Lisa {
+/// The evolutionary operators, used by every engine
+impl Lisa {
 
-    fn mutate(&mut self) {
-        match (rand() * 100.) as u8 {
-            0...10 => {
-                self.shapes.add_random(&self.ctx);
+    pub fn mutate(&mut self) {
+        let ctx = self.ctx.clone();
+        let mut rng = ctx.rng.lock().unwrap();
+        let rng = &mut *rng;
+        let op = self.ctx.operators.choose(&self.ctx.rates, self.ctx.adaptive_rates, rng);
+        self.ctx.operators.attempt(op);
+        self.applied.push(op);
+        match op {
+            Operator::Add => {
+                self.shapes.add_random(&self.ctx, rng);
                 self.mutation_appends += 1;
                 },
-            10...15 => {
-                self.shapes.remove_shape();
+            Operator::AddGuided => {
+                let canv = self.ctx.cache.canvas_for(&self.shapes);
+                self.shapes.add_guided(&self.ctx, &canv, rng);
+                self.mutation_guided += 1;
+                },
+            Operator::Remove => {
+                self.shapes.remove_shape(rng);
                 self.mutation_pops += 1;
                 },
-            15...20 => {
-                self.shapes.swap();
+            Operator::Swap => {
+                self.shapes.swap(rng);
                 self.mutation_swaps +=1;
                 }
-            20...100 => {
-                self.shapes.mutate();
+            Operator::Merge => {
+                if self.shapes.merge(&self.ctx, rng) {
+                    self.mutation_merges += 1;
+                }
+                }
+            Operator::Crossover => {
+                let partner = self.ctx.breeding_pool.lock().unwrap().random(rng);
+                if let Some(p) = partner {
+                    self.breed(&p, rng);
+                }
+                }
+            Operator::Insert => {
+                let guided = rand(rng) < 0.5;
+                self.shapes.insert_at_depth(&self.ctx, guided, rng);
+                self.mutation_inserts += 1;
+                }
+            Operator::Move => {
+                self.shapes.move_to_depth(rng);
+                self.mutation_moves += 1;
+                }
+            Operator::Split => {
+                self.shapes.split(rng);
+                self.mutation_splits += 1;
+                }
+            Operator::Change => {
+                let before = if self.ctx.solve_colors { Some(self.shapes.clone()) } else { None };
+                let changed = self.shapes.mutate(&self.ctx, rng);
+                if let (Some(i), Some(before)) = (changed, before) {
+                    // Geometry changed, so fit the colour to the new footprint
+                    if self.shapes.shapes[i].color() == before.shapes[i].color() {
+                        let below = self.ctx.cache.canvas_for(&before.slice(i));
+                        self.shapes.solve_color(&self.ctx, i, &below);
+                    }
+                }
                 self.mutation_changes += 1;
-                },
-            _ => panic!("Impossible mutation")
+                }
         }
         self.mutations += 1;
     }
 
-    fn calculate_fitness(&mut self) -> f64 {
+    pub fn calculate_fitness(&mut self) -> f64 {
         let ctx = Arc::clone(&mut self.ctx);
-        let mut cache = ctx.cache.lock().unwrap();
-		let canv = cache.canvas_for(&self.shapes);
+		let canv = ctx.cache.canvas_for(&self.shapes);
+        return Lisa::score(&ctx, &canv);
+    }
 
+    /// Fitness of a rendered canvas against the target
+    pub fn score(ctx: &Context, canv: &Canvas) -> f64 {
         if ctx.use_weighting {
             // Pixel difference * 100% + 0.01% per shape
             let fitness = canv.weighted_diff(&ctx.image, &ctx.weightings, 0.001);
@@ -166,24 +324,79 @@ impl Individual for Lisa {
         }
     }
 
-    fn reset(&mut self) {
-		self.shapes = ShapeList::new();
+    /// Start over after stagnating, as the context's restart policy says
+    pub fn reset(&mut self) {
+        let restart = self.ctx.restart;
+        match restart {
+            Restart::Wipe => self.wipe(),
+            Restart::Perturb(n) => {
+                for _ in 0..n {
+                    self.mutate();
+                }
+            },
+            Restart::Truncate(k) => self.shapes.drop_top(k),
+            Restart::Reseed(fraction) => {
+                let r = rand(&mut self.ctx.rng.lock().unwrap());
+                if r >= fraction {
+                    // Spared, so nothing to report
+                    return;
+                }
+                self.wipe();
+                self.mutate();
+            },
+            Restart::Boost(factor) => self.shapes.scale_steps(factor)
+        }
+        // Nothing applied before the restart earned it
+        self.applied.clear();
+        println!("# Reset genome ({:?}), now {} shapes", restart, self.shapes.len());
+    }
+
+    pub fn wipe(&mut self) {
+		self.shapes = ShapeList::with_background(self.ctx.mean_color.clone());
     }
 
-	fn new_fittest_found(&mut self) {
+	pub fn new_fittest_found(&mut self) {
+        // The only acceptance darwin-rs tells us about, the native
+        // engines have already credited theirs
+        self.accept();
+        self.cache_shapes();
+        self.ctx.breeding_pool.lock().unwrap().add(&self.shapes);
+
+        // Islands each find their own bests, only the overall best is
+        // written out. The lock is held until it has been, so a worse
+        // island can't overwrite it.
+        let fitness = self.calculate_fitness();
+        let ctx = self.ctx.clone();
+        let mut best = ctx.best_fitness.lock().unwrap();
+        if fitness >= *best {
+            return;
+        }
+        *best = fitness;
         let now = chrono::Utc::now();
 		print!("{} New fittest: {} \n", now, self.str());
-        self.ctx.cache.lock().unwrap().insert(&self.shapes);
+        if let Some(ref lineage) = self.ctx.lineage {
+            lineage.lock().unwrap().record(&self.shapes, fitness, self.mutations);
+        }
         //let filename = format!("{}.svg", (self.calculate_fitness() / 1000_000.) as u32);
-        let mut svg = File::create("best.svg").unwrap();
-        std::io::Write::write_all(&mut svg, self.svg().as_bytes()).expect("couldn't write");
+        self.save("best");
+    }
+}
 
-        let mut jsonfile = File::create("best.json").unwrap();
-        std::io::Write::write_all(&mut jsonfile,
-                serde_json::to_string(&self.serialize()).expect("Serialize error").as_bytes()
-            ).expect("couldn't write json");
+#[cfg(feature = "darwin")]
+impl Individual for Lisa {
+    fn mutate(&mut self) {
+        Lisa::mutate(self);
+    }
 
-        self.ctx.cache.lock().unwrap().canvas_for(&self.shapes).save("best.png");
+    fn calculate_fitness(&mut self) -> f64 {
+        return Lisa::calculate_fitness(self);
     }
-}
 
+    fn reset(&mut self) {
+        Lisa::reset(self);
+    }
+
+    fn new_fittest_found(&mut self) {
+        Lisa::new_fittest_found(self);
+    }
+}
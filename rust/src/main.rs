@@ -8,6 +8,7 @@
 #![cfg_attr(feature="clippy", feature(plugin))]
 #![cfg_attr(feature="clippy", plugin(clippy))]
 
+#[cfg(feature = "darwin")]
 extern crate darwin_rs;
 extern crate serde;
 extern crate serde_json;
@@ -19,6 +20,7 @@ extern crate jpeg_decoder;
 #[macro_use] extern crate clap;
 
 pub mod rando;
+pub mod steps;
 pub mod canvas;
 pub mod color;
 pub mod shapes;
@@ -26,12 +28,42 @@ pub mod shapelist;
 pub mod lisa;
 pub mod context;
 pub mod canvascache;
+pub mod palette;
+pub mod guide;
+pub mod colorsolve;
+pub mod rates;
+pub mod pool;
+pub mod prune;
+pub mod refine;
+pub mod lineage;
+pub mod anneal;
+pub mod engine;
+pub mod evolution;
+pub mod islands;
+pub mod de;
+pub mod greedy;
+pub mod stagnation;
+pub mod diversity;
 
-use darwin_rs::{Individual, SimulationBuilder, PopulationBuilder};
-use clap::{Arg, App};
+#[cfg(feature = "darwin")]
+use darwin_rs::{SimulationBuilder, PopulationBuilder};
+use clap::{Arg, App, ArgMatches, SubCommand};
 use lisa::Lisa;
 use context::Context;
-use std::sync::Arc;
+use palette::Palette;
+use rates::MutationRates;
+use lineage::Lineage;
+use anneal::{Annealer, Anneal, Schedule};
+use engine::Engine;
+use evolution::{Evolution, EvolutionConfig, Strategy, Selection};
+use islands::{Islands, Topology};
+use de::DeConfig;
+use greedy::{Greedy, GreedyConfig};
+use stagnation::{Stagnation, Restart};
+use diversity::{Distance, Niching};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
 
 fn main() {
     env_logger::init().expect("logger couldn't init");
@@ -56,44 +88,327 @@ fn main() {
                       .long("xc"))
                  .arg(Arg::with_name("no-rects")
                       .long("xr"))
+                 .arg(Arg::with_name("margin")
+                      .short("m")
+                      .long("margin")
+                      .takes_value(true))
+                 .arg(Arg::with_name("palette")
+                      .long("palette")
+                      .takes_value(true))
+                 .arg(Arg::with_name("palette-size")
+                      .long("palette-size")
+                      .takes_value(true))
+                 .arg(Arg::with_name("palette-opacity")
+                      .long("palette-opacity")
+                      .takes_value(true))
+                 .arg(Arg::with_name("opaque")
+                      .long("opaque"))
+                 .arg(Arg::with_name("solve-colors")
+                      .long("solve-colors"))
+                 .arg(Arg::with_name("rates")
+                      .long("rates")
+                      .takes_value(true))
+                 .arg(Arg::with_name("adaptive-rates")
+                      .long("adaptive-rates"))
+                 .arg(Arg::with_name("crossover")
+                      .short("x")
+                      .long("crossover")
+                      .takes_value(true))
+                 .arg(Arg::with_name("prune")
+                      .long("prune")
+                      .takes_value(true))
+                 .arg(Arg::with_name("refine")
+                      .long("refine")
+                      .takes_value(true))
+                 .arg(Arg::with_name("de")
+                      .long("de")
+                      .takes_value(true))
+                 .arg(Arg::with_name("lineage")
+                      .long("lineage")
+                      .takes_value(true))
+                 .arg(Arg::with_name("seed")
+                      .long("seed")
+                      .takes_value(true))
+                 .arg(Arg::with_name("engine")
+                      .long("engine")
+                      .possible_values(&["native", "darwin", "anneal", "greedy"])
+                      .takes_value(true))
+                 .arg(Arg::with_name("lambda")
+                      .long("lambda")
+                      .takes_value(true))
+                 .arg(Arg::with_name("comma")
+                      .long("comma"))
+                 .arg(Arg::with_name("selection")
+                      .long("selection")
+                      .possible_values(&["tournament", "truncation"])
+                      .takes_value(true))
+                 .arg(Arg::with_name("tournament")
+                      .long("tournament")
+                      .takes_value(true))
+                 .arg(Arg::with_name("elitism")
+                      .long("elitism")
+                      .takes_value(true))
+                 .arg(Arg::with_name("mutations")
+                      .long("mutations")
+                      .takes_value(true))
+                 .arg(Arg::with_name("generations")
+                      .long("generations")
+                      .takes_value(true))
+                 .arg(Arg::with_name("islands")
+                      .long("islands")
+                      .takes_value(true))
+                 .arg(Arg::with_name("threads")
+                      .long("threads")
+                      .takes_value(true))
+                 .arg(Arg::with_name("migrate")
+                      .long("migrate")
+                      .takes_value(true))
+                 .arg(Arg::with_name("topology")
+                      .long("topology")
+                      .possible_values(&["ring", "full", "random"])
+                      .takes_value(true))
+                 .arg(Arg::with_name("schedule")
+                      .long("schedule")
+                      .possible_values(&["exp", "linear", "reheat"])
+                      .takes_value(true))
+                 .arg(Arg::with_name("temperature")
+                      .long("temperature")
+                      .takes_value(true))
+                 .arg(Arg::with_name("steps")
+                      .long("steps")
+                      .takes_value(true))
+                 .arg(Arg::with_name("checkpoint")
+                      .long("checkpoint")
+                      .takes_value(true))
+                 .arg(Arg::with_name("resume")
+                      .long("resume"))
+                 .arg(Arg::with_name("niching")
+                      .long("niching")
+                      .possible_values(&["none", "sharing", "crowding"])
+                      .takes_value(true))
+                 .arg(Arg::with_name("share-radius")
+                      .long("share-radius")
+                      .takes_value(true))
+                 .arg(Arg::with_name("distance")
+                      .long("distance")
+                      .possible_values(&["shapes", "canvas"])
+                      .takes_value(true))
+                 .arg(Arg::with_name("restart")
+                      .long("restart")
+                      .possible_values(&["wipe", "perturb", "truncate", "reseed", "boost"])
+                      .takes_value(true))
+                 .arg(Arg::with_name("restart-amount")
+                      .long("restart-amount")
+                      .takes_value(true))
+                 .arg(Arg::with_name("reset-start")
+                      .long("reset-start")
+                      .takes_value(true))
+                 .arg(Arg::with_name("reset-increment")
+                      .long("reset-increment")
+                      .takes_value(true))
+                 .arg(Arg::with_name("reset-end")
+                      .long("reset-end")
+                      .takes_value(true))
+                 .arg(Arg::with_name("shapes")
+                      .long("shapes")
+                      .takes_value(true))
+                 .arg(Arg::with_name("candidates")
+                      .long("candidates")
+                      .takes_value(true))
+                 .arg(Arg::with_name("climb")
+                      .long("climb")
+                      .takes_value(true))
+                 .subcommand(SubCommand::with_name("prune")
+                      .about("Remove shapes that barely affect fitness from a saved genome")
+                      .arg(Arg::with_name("genome")
+                           .short("f")
+                           .takes_value(true))
+                      .arg(Arg::with_name("threshold")
+                           .short("t")
+                           .takes_value(true))
+                      .arg(Arg::with_name("output")
+                           .short("o")
+                           .takes_value(true)))
+                 .subcommand(SubCommand::with_name("refine")
+                      .about("Coordinate descent over the parameters of a saved genome")
+                      .arg(Arg::with_name("genome")
+                           .short("f")
+                           .takes_value(true))
+                      .arg(Arg::with_name("passes")
+                           .short("n")
+                           .takes_value(true))
+                      .arg(Arg::with_name("delta")
+                           .short("d")
+                           .takes_value(true))
+                      .arg(Arg::with_name("output")
+                           .short("o")
+                           .takes_value(true)))
+                 .subcommand(SubCommand::with_name("de")
+                      .about("Differential evolution of the parameters of a saved genome, a window of shapes at a time")
+                      .arg(Arg::with_name("genome")
+                           .short("f")
+                           .takes_value(true))
+                      .arg(Arg::with_name("window")
+                           .short("w")
+                           .takes_value(true))
+                      .arg(Arg::with_name("generations")
+                           .short("g")
+                           .takes_value(true))
+                      .arg(Arg::with_name("output")
+                           .short("o")
+                           .takes_value(true)))
+                 .subcommand(SubCommand::with_name("bench")
+                      .about("Time the native engine on one thread and on many")
+                      .arg(Arg::with_name("generations")
+                           .short("g")
+                           .takes_value(true)))
+                 .subcommand(SubCommand::with_name("replay")
+                      .about("Rebuild a genome from a lineage file")
+                      .arg(Arg::with_name("lineage")
+                           .short("f")
+                           .takes_value(true))
+                      .arg(Arg::with_name("steps")
+                           .short("n")
+                           .takes_value(true))
+                      .arg(Arg::with_name("output")
+                           .short("o")
+                           .takes_value(true))
+                      .arg(Arg::with_name("frames")
+                           .long("frames")
+                           .takes_value(true)))
                  .get_matches();
 
-    let population = value_t!(matches.value_of("population"), usize).unwrap_or(3);
-    let growth = value_t!(matches.value_of("exponential-growth"), f64).unwrap_or(1.0);
-    let start_with_best = matches.is_present("loadbest"); 
-    let use_weighting = matches.is_present("weighting"); 
-    let image = value_t!(matches.value_of("image"), String).unwrap_or(String::from("lisa.jpg"));
+    let ctx = context_from(&matches);
 
-    let use_triangles = !matches.is_present("no-triangles");
-    let use_circles = !matches.is_present("no-circles");
-    let use_rectangles = !matches.is_present("no-rects");
+    if let Some(sub) = matches.subcommand_matches("prune") {
+        let genome = sub.value_of("genome").unwrap_or("best.json");
+        let threshold = value_t!(sub.value_of("threshold"), f64).unwrap_or(0.0005);
+        let output = sub.value_of("output").unwrap_or("pruned");
+        let mut lisa = Lisa::load(ctx, genome);
+        run_prune(&mut lisa, threshold);
+        lisa.save(output);
+        return;
+    }
 
-    let mut context = Context::new(&image, use_weighting, use_triangles, use_circles, use_rectangles);
+    if let Some(sub) = matches.subcommand_matches("refine") {
+        let genome = sub.value_of("genome").unwrap_or("best.json");
+        let passes = value_t!(sub.value_of("passes"), usize).unwrap_or(10);
+        let delta = value_t!(sub.value_of("delta"), f32).unwrap_or(0.05);
+        let output = sub.value_of("output").unwrap_or("refined");
+        let mut lisa = Lisa::load(ctx, genome);
+        run_refine(&mut lisa, passes, delta);
+        lisa.save(output);
+        return;
+    }
 
-    if context.use_weighting {
-        context.weight_entropy();
+    if let Some(sub) = matches.subcommand_matches("de") {
+        let genome = sub.value_of("genome").unwrap_or("best.json");
+        let output = sub.value_of("output").unwrap_or("de");
+        let mut cfg = DeConfig::default();
+        cfg.window = value_t!(sub.value_of("window"), usize).unwrap_or(cfg.window);
+        cfg.generations = value_t!(sub.value_of("generations"), usize).unwrap_or(cfg.generations);
+        cfg.threads = value_t!(matches.value_of("threads"), usize).unwrap_or(cores());
+        let mut lisa = Lisa::load(ctx, genome);
+        run_de(&mut lisa, &cfg);
+        lisa.save(output);
+        return;
     }
-    let ctx = Arc::new(context);
+
+    if let Some(sub) = matches.subcommand_matches("replay") {
+        let entries = lineage::read(sub.value_of("lineage").unwrap_or("lineage.jsonl"));
+        let steps = value_t!(sub.value_of("steps"), usize).unwrap_or(entries.len());
+        let output = sub.value_of("output").unwrap_or("replayed");
+        let frames = sub.value_of("frames");
+        let (w, h, d) = (ctx.width as usize, ctx.height as usize, ctx.depth as usize);
+        let shapes = lineage::replay(&entries, steps, |i, sl| {
+            if let Some(prefix) = frames {
+                let mut canv = sl.blank_canvas(w, h, d);
+                sl.draw_onto(&mut canv);
+                canv.save(&format!("{}-{:05}.png", prefix, i));
+            }
+        });
+        println!("# Replayed {} of {} steps, {} shapes", steps.min(entries.len()), entries.len(), shapes.len());
+        let mut lisa = Lisa::new(ctx);
+        lisa.shapes = shapes;
+        lisa.save(output);
+        return;
+    }
+
+    let population = value_t!(matches.value_of("population"), usize).unwrap_or(3);
+    assert!(population > 0, "population must be at least 1");
+
+    if let Some(sub) = matches.subcommand_matches("bench") {
+        let generations = value_t!(sub.value_of("generations"), u64).unwrap_or(200);
+        run_bench(&matches, ctx, population, generations);
+        return;
+    }
+
+    let start_with_best = matches.is_present("loadbest"); 
     let mut my_pop;
 
-	println!("# Loaded source image {}x{} {:?}", ctx.width, ctx.height, ctx.format);
-    println!("# Using T:{} C:{} R:{}", ctx.use_triangles, ctx.use_circles, ctx.use_rectangles);
     if start_with_best {
         my_pop = Lisa::make_population_from_file(population, ctx.clone(), "best.json");
         println!("# - Using previous best: {:.1}, {}",
                  my_pop[0].calculate_fitness(), my_pop[0].shapes.len());
-        ctx.cache.lock().unwrap().insert(&my_pop[0].shapes);
+        ctx.cache.insert(&my_pop[0].shapes);
     } else {
-	    my_pop = Lisa::make_population(population, ctx);
+	    my_pop = Lisa::make_population(population, ctx.clone());
     }
 	println!("# Allocated individuals: {}", population);
+
+    let engine = matches.value_of("engine").unwrap_or("native");
+    let best = if engine == "darwin" {
+        run_darwin(&matches, my_pop)
+    } else {
+        let islands = value_t!(matches.value_of("islands"), usize).unwrap_or(1);
+        let mut engine: Box<dyn Engine> = match engine {
+            "anneal" => Box::new(anneal_from(&matches, my_pop.remove(0))),
+            "greedy" => Box::new(greedy_from(&matches, ctx.clone(), start_with_best, my_pop)),
+            _ if islands > 1 => Box::new(islands_from(&matches, ctx.clone(), my_pop, islands)),
+            _ => Box::new(Evolution::new(evolution_config(&matches, my_pop.len()), my_pop))
+        };
+        Some(engine.run())
+    };
+
+    if let Some(mut best) = best {
+        let prune = value_t!(matches.value_of("prune"), f64);
+        let de = value_t!(matches.value_of("de"), usize);
+        let refine = value_t!(matches.value_of("refine"), usize);
+        if let Ok(threshold) = prune {
+            run_prune(&mut best, threshold);
+        }
+        if let Ok(window) = de {
+            let mut cfg = DeConfig::default();
+            cfg.window = window;
+            cfg.threads = value_t!(matches.value_of("threads"), usize).unwrap_or(cores());
+            run_de(&mut best, &cfg);
+        }
+        if let Ok(passes) = refine {
+            run_refine(&mut best, passes, 0.05);
+        }
+        if prune.is_ok() || de.is_ok() || refine.is_ok() {
+            best.save("best");
+        }
+    }
+}
+
+#[cfg(not(feature = "darwin"))]
+fn run_darwin(_: &ArgMatches, _: Vec<Lisa>) -> Option<Lisa> {
+    println!("# Built without darwin-rs, rebuild with --features darwin");
+    return None;
+}
+
+#[cfg(feature = "darwin")]
+fn run_darwin(matches: &ArgMatches, my_pop: Vec<Lisa>) -> Option<Lisa> {
+    let growth = value_t!(matches.value_of("exponential-growth"), f64).unwrap_or(1.0);
+    let stagnation = stagnation_from(matches);
 	let population = PopulationBuilder::<Lisa>::new()
 		.set_id(1)
 		.initial_population(&my_pop)
 		.increasing_exp_mutation_rate(growth)
-		.reset_limit_increment(100)
-		.reset_limit_start(100)
-		.reset_limit_end(0)
+		.reset_limit_increment(stagnation.increment as u32)
+		.reset_limit_start(stagnation.start as u32)
+		.reset_limit_end(stagnation.end as u32)
 		.finalize().unwrap();
 	println!("# Built population");
 	let simulation = SimulationBuilder::<Lisa>::new()
@@ -105,7 +420,10 @@ fn main() {
 
 
 	match simulation {
-		Err(e) => println!("unexpected error: {}", e),
+		Err(e) => {
+			println!("unexpected error: {}", e);
+			return None;
+		},
 
 		Ok(mut simulation) => {
 			println!("Starting run");
@@ -115,6 +433,220 @@ fn main() {
 			for res in &simulation.simulation_result.fittest {
 				print!("- {} {}", res.fitness, res.individual.svg());
 			}
+			return Some(simulation.simulation_result.fittest[0].individual.clone());
 		}
 	}
 }
+
+fn evolution_config(matches: &ArgMatches, mu: usize) -> EvolutionConfig {
+    let mut cfg = EvolutionConfig::default();
+    cfg.mu = mu;
+    cfg.lambda = value_t!(matches.value_of("lambda"), usize).unwrap_or(cfg.mu * 2);
+    if matches.is_present("comma") {
+        cfg.strategy = Strategy::Comma;
+    }
+    let k = value_t!(matches.value_of("tournament"), usize).unwrap_or(2);
+    cfg.selection = match matches.value_of("selection") {
+        Some("truncation") => Selection::Truncation,
+        _ => Selection::Tournament(k)
+    };
+    cfg.elitism = value_t!(matches.value_of("elitism"), usize).unwrap_or(cfg.elitism);
+    cfg.mutations = value_t!(matches.value_of("mutations"), usize).unwrap_or(cfg.mutations);
+    cfg.crossover = value_t!(matches.value_of("crossover"), f32).unwrap_or(cfg.crossover);
+    cfg.generations = value_t!(matches.value_of("generations"), u64).ok();
+    cfg.threads = value_t!(matches.value_of("threads"), usize).unwrap_or(cores());
+    cfg.stagnation = stagnation_from(matches);
+    if let Some(d) = matches.value_of("distance") {
+        cfg.distance = Distance::parse(d).expect("distance must be shapes or canvas");
+    }
+    let radius = value_t!(matches.value_of("share-radius"), f64).unwrap_or(0.1);
+    cfg.niching = match matches.value_of("niching") {
+        Some("sharing") => Niching::Sharing(radius),
+        Some("crowding") => Niching::Crowding,
+        _ => Niching::None
+    };
+    println!("# Native engine: ({}{}{}) {:?}, elitism {}, crossover {}, niching {:?} by {:?}",
+             cfg.mu, if cfg.strategy == Strategy::Plus { "+" } else { "," }, cfg.lambda,
+             cfg.selection, cfg.elitism, cfg.crossover, cfg.niching, cfg.distance);
+    return cfg;
+}
+
+/// Restart after `--reset-start` stale generations, then that plus
+/// `--reset-increment` and so on up to `--reset-end`. Off unless
+/// `--reset-end` or `--restart` is given, `--restart` alone wraps after
+/// ten increments.
+fn stagnation_from(matches: &ArgMatches) -> Stagnation {
+    let mut st = Stagnation::default();
+    st.start = value_t!(matches.value_of("reset-start"), u64).unwrap_or(st.start);
+    st.increment = value_t!(matches.value_of("reset-increment"), u64).unwrap_or(st.increment);
+    if matches.is_present("restart") {
+        st.end = st.start + 10 * st.increment.max(1);
+    }
+    st.end = value_t!(matches.value_of("reset-end"), u64).unwrap_or(st.end);
+    if st.end > 0 {
+        println!("# Restart after {} stale generations (+{} each time, up to {})",
+                 st.start, st.increment, st.end);
+    }
+    return st;
+}
+
+fn cores() -> usize {
+    return thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+}
+
+/// The same seeded run on one thread and on `--threads`, which should
+/// reach the same fitness
+fn run_bench(matches: &ArgMatches, ctx: Arc<Context>, mu: usize, generations: u64) {
+    let mut cfg = evolution_config(matches, mu);
+    cfg.generations = Some(generations);
+    if matches.value_of("lambda").is_none() {
+        cfg.lambda = cfg.lambda.max(cfg.threads * 4);
+    }
+    let mut times = Vec::new();
+    for &threads in [1, cfg.threads].iter() {
+        let mut c = ctx.island(0);
+        // Never write anything out
+        c.best_fitness = Arc::new(Mutex::new(::std::f64::NEG_INFINITY));
+        let c = Arc::new(c);
+        let mut run = cfg.clone();
+        run.threads = threads;
+        let start = Instant::now();
+        let mut best = Evolution::new(run, Lisa::make_population(mu, c)).run();
+        let elapsed = start.elapsed();
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+        println!("# {} thread(s), λ={}: {} generations in {:.2}s ({:.1}/s), fitness {:.1}",
+                 threads, cfg.lambda, generations, secs, generations as f64 / secs,
+                 best.calculate_fitness());
+        times.push(secs);
+    }
+    println!("# Speedup: {:.2}x", times[0] / times[1]);
+}
+
+fn islands_from(matches: &ArgMatches, ctx: Arc<Context>, my_pop: Vec<Lisa>, n: usize) -> Islands {
+    let cfg = evolution_config(matches, my_pop.len());
+    let interval = value_t!(matches.value_of("migrate"), u64).unwrap_or(50);
+    let topology = Topology::parse(matches.value_of("topology").unwrap_or("ring"))
+        .expect("topology must be ring, full or random");
+    println!("# {} islands, {:?} migration every {} generations", n, topology, interval);
+    return Islands::new(cfg, ctx, my_pop, n, topology, interval);
+}
+
+/// Builds on the saved best with -b, otherwise from a bare background
+fn greedy_from(matches: &ArgMatches, ctx: Arc<Context>, start_with_best: bool, mut my_pop: Vec<Lisa>) -> Greedy {
+    let mut cfg = GreedyConfig::default();
+    cfg.shapes = value_t!(matches.value_of("shapes"), usize).unwrap_or(cfg.shapes);
+    cfg.candidates = value_t!(matches.value_of("candidates"), usize).unwrap_or(cfg.candidates);
+    cfg.climb = value_t!(matches.value_of("climb"), usize).unwrap_or(cfg.climb);
+    cfg.threads = value_t!(matches.value_of("threads"), usize).unwrap_or(cores());
+    let start = if start_with_best { my_pop.remove(0) } else { Lisa::new(ctx) };
+    println!("# Greedy: up to {} shapes, {} candidates, climbing {} of them {} steps",
+             cfg.shapes, cfg.candidates, cfg.climbers, cfg.climb);
+    return Greedy::new(cfg, start);
+}
+
+fn anneal_from(matches: &ArgMatches, start: Lisa) -> Anneal {
+    let mut annealer = Annealer::default();
+    if let Some(s) = matches.value_of("schedule") {
+        annealer.schedule = Schedule::parse(s).expect("schedule must be exp, linear or reheat");
+    }
+    if let Ok(t) = value_t!(matches.value_of("temperature"), f64) {
+        annealer.t0 = t;
+    }
+    if let Ok(steps) = value_t!(matches.value_of("steps"), u64) {
+        annealer.steps = steps;
+    }
+    if let Ok(n) = value_t!(matches.value_of("checkpoint"), u64) {
+        annealer.checkpoint_every = n.max(1);
+    }
+    if matches.is_present("resume") {
+        return annealer.resume(start.ctx.clone());
+    }
+    return annealer.start(start);
+}
+
+fn context_from(matches: &ArgMatches) -> Arc<Context> {
+    let use_weighting = matches.is_present("weighting"); 
+    let image = value_t!(matches.value_of("image"), String).unwrap_or(String::from("lisa.jpg"));
+
+    let use_triangles = !matches.is_present("no-triangles");
+    let use_circles = !matches.is_present("no-circles");
+    let use_rectangles = !matches.is_present("no-rects");
+    let margin = value_t!(matches.value_of("margin"), f32).unwrap_or(0.);
+
+    let mut context = Context::new(&image, use_weighting, use_triangles, use_circles, use_rectangles);
+    if let Ok(seed) = value_t!(matches.value_of("seed"), u64) {
+        context.reseed(seed);
+    }
+    context.margin = margin;
+    if let Some(path) = matches.value_of("palette") {
+        context.palette = Some(Palette::from_file(path));
+    } else if let Ok(n) = value_t!(matches.value_of("palette-size"), usize) {
+        context.palette = Some(Palette::from_image(&context.image, n, &mut context.rng.lock().unwrap()));
+    }
+    if let Some(ref p) = context.palette {
+        // Genomes start from this background, so it has to be an ink too
+        context.mean_color = p.colors[p.nearest(&context.mean_color)].clone();
+    }
+    context.palette_opacity = value_t!(matches.value_of("palette-opacity"), f32).ok();
+    context.opaque = matches.is_present("opaque");
+    context.solve_colors = matches.is_present("solve-colors");
+    if let Some(path) = matches.value_of("rates") {
+        context.rates = MutationRates::from_file(path);
+    }
+    context.adaptive_rates = matches.is_present("adaptive-rates");
+    if let Some(name) = matches.value_of("restart") {
+        let amount = value_t!(matches.value_of("restart-amount"), f32).ok();
+        context.restart = Restart::parse(name, amount).expect("unknown restart policy");
+    }
+    // Population engines breed two parents themselves, the rest breed
+    // with the pool of recent bests
+    let single = match matches.value_of("engine") {
+        Some("darwin") | Some("anneal") => true,
+        _ => false
+    };
+    if let (Ok(rate), true) = (value_t!(matches.value_of("crossover"), f32), single) {
+        context.rates.crossover = rate;
+    }
+
+    // Replaying only reads the lineage
+    let replaying = matches.subcommand_name() == Some("replay");
+    if let (Some(path), false) = (matches.value_of("lineage"), replaying) {
+        context.lineage = Some(Arc::new(Mutex::new(Lineage::open(path))));
+    }
+
+    if context.use_weighting {
+        context.weight_entropy();
+    }
+    let ctx = Arc::new(context);
+
+	println!("# Loaded source image {}x{} {:?}", ctx.width, ctx.height, ctx.format);
+    println!("# Seed: {}", ctx.seed);
+    println!("# Using T:{} C:{} R:{} margin:{} opaque:{}",
+             ctx.use_triangles, ctx.use_circles, ctx.use_rectangles, ctx.margin, ctx.opaque);
+    if let Some(ref p) = ctx.palette {
+        let inks: Vec<String> = (0..p.len()).map(|i| p.hex(i)).collect();
+        println!("# Palette: {}", inks.join(" "));
+    }
+    return ctx;
+}
+
+fn run_prune(lisa: &mut Lisa, threshold: f64) {
+    let report = prune::prune(lisa, threshold);
+    println!("# Pruned {} of {} shapes, fitness {:.1} -> {:.1} ({:+.1})",
+             report.removed(), report.shapes_before,
+             report.fitness_before, report.fitness_after, report.delta());
+}
+
+fn run_refine(lisa: &mut Lisa, passes: usize, delta: f32) {
+    let report = refine::refine(lisa, delta, 0.001, passes);
+    println!("# Refined in {} passes, {} improvements, fitness {:.1} -> {:.1}",
+             report.passes, report.improvements,
+             report.fitness_before, report.fitness_after);
+}
+
+fn run_de(lisa: &mut Lisa, cfg: &DeConfig) {
+    let report = de::optimise(lisa, cfg);
+    println!("# DE improved {} of {} windows, fitness {:.1} -> {:.1}",
+             report.improved, report.windows,
+             report.fitness_before, report.fitness_after);
+}
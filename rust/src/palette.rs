@@ -0,0 +1,208 @@
+// Restricted colour sets, for output that has to be printed with a
+// limited number of inks.
+
+use color::Color;
+use canvas::Canvas;
+use rando::{Rng, rand};
+use std::fs::File;
+use std::io::Read;
+use std::fmt::Write;
+
+// Pixels sampled from the target when clustering
+const KMEANS_SAMPLES: usize = 10000;
+const KMEANS_ITERATIONS: usize = 20;
+
+#[derive(Debug, Clone)]
+pub struct Palette {
+    pub colors: Vec<Color>
+}
+
+fn distance_sq(a: &Color, b: &Color) -> f32 {
+    return (a.r - b.r) * (a.r - b.r) +
+           (a.g - b.g) * (a.g - b.g) +
+           (a.b - b.b) * (a.b - b.b);
+}
+
+fn nearest(colors: &[Color], c: &Color) -> usize {
+    let mut best = 0;
+    for i in 1..colors.len() {
+        if distance_sq(c, &colors[i]) < distance_sq(c, &colors[best]) {
+            best = i;
+        }
+    }
+    return best;
+}
+
+impl Palette {
+    /// One colour per line, as `#rrggbb` or `#rgb`. Blank lines are
+    /// ignored.
+    pub fn parse(text: &str) -> Result<Palette, String> {
+        let mut colors = Vec::new();
+        for line in text.lines() {
+            let hex = line.trim().trim_start_matches('#');
+            if hex.len() == 0 {
+                continue;
+            }
+            if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(format!("Invalid palette colour: {}", line));
+            }
+            let digits: Vec<u8> = hex.chars().map(|c| c.to_digit(16).unwrap() as u8).collect();
+            let channel = |i: usize| match digits.len() {
+                3 => (digits[i] * 17) as f32,
+                _ => (digits[i * 2] * 16 + digits[i * 2 + 1]) as f32
+            };
+            match digits.len() {
+                3 | 6 => colors.push(Color { r: channel(0), g: channel(1), b: channel(2), opacity: 1. }),
+                _ => return Err(format!("Palette colours need 3 or 6 hex digits: {}", line))
+            }
+        }
+        if colors.len() == 0 {
+            return Err(String::from("Empty palette"));
+        }
+        return Ok(Palette { colors: colors });
+    }
+
+    pub fn from_file(path: &str) -> Palette {
+        let mut text = String::new();
+        File::open(path).expect("failed to open palette")
+            .read_to_string(&mut text).expect("failed to read palette");
+        return Palette::parse(&text).unwrap_or_else(|e| panic!("{}: {}", path, e));
+    }
+
+    /// Extract `n` colours from an image with k-means clustering
+    pub fn from_image(image: &Canvas, n: usize, rng: &mut Rng) -> Palette {
+        let n = n.max(1);
+        let stride = (image.width * image.height / KMEANS_SAMPLES).max(1);
+        let mut samples = Vec::new();
+        let mut i = 0;
+        while i < image.width * image.height {
+            samples.push(image.pixel_at((i % image.width) as i32, (i / image.width) as i32));
+            i += stride;
+        }
+
+        let mut centers: Vec<Color> = (0..n)
+            .map(|_| samples[(rand(rng) * samples.len() as f32) as usize].clone())
+            .collect();
+
+        for _ in 0..KMEANS_ITERATIONS {
+            let mut sums = vec![(0f64, 0f64, 0f64, 0usize); n];
+            for s in &samples {
+                let c = nearest(&centers, s);
+                sums[c].0 += s.r as f64;
+                sums[c].1 += s.g as f64;
+                sums[c].2 += s.b as f64;
+                sums[c].3 += 1;
+            }
+            for c in 0..n {
+                let (r, g, b, count) = sums[c];
+                if count == 0 {
+                    // Reseed empty clusters
+                    centers[c] = samples[(rand(rng) * samples.len() as f32) as usize].clone();
+                } else {
+                    centers[c] = Color {
+                        r: (r / count as f64) as f32,
+                        g: (g / count as f64) as f32,
+                        b: (b / count as f64) as f32,
+                        opacity: 1.
+                    };
+                }
+            }
+        }
+        return Palette { colors: centers };
+    }
+
+    pub fn len(&self) -> usize {
+        return self.colors.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.colors.is_empty();
+    }
+
+    fn random_index(&self, rng: &mut Rng) -> usize {
+        return (rand(rng) * self.colors.len() as f32) as usize;
+    }
+
+    pub fn random(&self, rng: &mut Rng) -> Color {
+        return self.colors[self.random_index(rng)].clone();
+    }
+
+    pub fn nearest(&self, c: &Color) -> usize {
+        return nearest(&self.colors, c);
+    }
+
+    /// The nearest entry, with the opacity of `c`
+    pub fn snap(&self, c: &Color) -> Color {
+        let mut snapped = self.colors[self.nearest(c)].clone();
+        snapped.opacity = c.opacity;
+        return snapped;
+    }
+
+    pub fn index_of(&self, c: &Color) -> Option<usize> {
+        let i = self.nearest(c);
+        if distance_sq(c, &self.colors[i]) < 1. {
+            return Some(i);
+        }
+        return None;
+    }
+
+    /// Jump to a different entry, keeping the opacity
+    pub fn mutate(&self, c: &Color, rng: &mut Rng) -> Color {
+        let current = self.nearest(c);
+        let mut i = self.random_index(rng);
+        while i == current && self.len() > 1 {
+            i = self.random_index(rng);
+        }
+        let mut next = self.colors[i].clone();
+        next.opacity = c.opacity;
+        return next;
+    }
+
+    pub fn hex(&self, i: usize) -> String {
+        let c = &self.colors[i];
+        return format!("#{:02x}{:02x}{:02x}", c.r as u8, c.g as u8, c.b as u8);
+    }
+
+    /// CSS classes `.p0`, `.p1`... for each ink
+    pub fn svg_style(&self) -> String {
+		let mut out = String::new();
+        out.push_str("<style>");
+        for i in 0..self.colors.len() {
+            write!(&mut out, ".p{}{{fill:{}}}", i, self.hex(i)).expect("String concat failed");
+        }
+        out.push_str("</style>");
+        return out;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let p = Palette::parse("#ff0000\n\n00ff80\n#fa0\n").unwrap();
+        assert_eq!(p.len(), 3);
+        assert_eq!(p.hex(1), "#00ff80");
+        assert_eq!(p.hex(2), "#ffaa00");
+        assert_eq!(p.index_of(&Color { r: 255., g: 0., b: 0., opacity: 0.5 }), Some(0));
+
+        assert!(Palette::parse("#ff00\n").unwrap_err().contains("#ff00"));
+        assert!(Palette::parse("#gg0000\n").is_err());
+        assert!(Palette::parse("\n").is_err());
+    }
+
+    #[test]
+    fn kmeans() {
+        let mut c = Canvas::new(10, 10, 3);
+        for x in 0..5 {
+            for y in 0..10 {
+                c.add_pixel(x, y, &Color { r: 200., g: 100., b: 50., opacity: 1. });
+            }
+        }
+        let p = Palette::from_image(&c, 2, &mut Rng::new(1));
+        assert!(p.index_of(&Color { r: 200., g: 100., b: 50., opacity: 1. }).is_some());
+        assert!(p.index_of(&Color::black()).is_some());
+        assert_eq!(Palette::from_image(&c, 0, &mut Rng::new(1)).len(), 1);
+    }
+}
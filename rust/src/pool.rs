@@ -0,0 +1,36 @@
+// A pool of recent fittest genomes to breed from, much like the random
+// strains the couchdb version handed out to browsers.
+
+use shapelist::ShapeList;
+use rando::{Rng, rand};
+use std::collections::VecDeque;
+
+#[derive(Debug, Clone)]
+pub struct BreedingPool {
+    genomes: VecDeque<ShapeList>,
+    capacity: usize
+}
+
+impl BreedingPool {
+    pub fn new(capacity: usize) -> BreedingPool {
+        BreedingPool {
+            genomes: VecDeque::with_capacity(capacity),
+            capacity: capacity
+        }
+    }
+
+    pub fn add(&mut self, sl: &ShapeList) {
+        if self.genomes.len() >= self.capacity {
+            self.genomes.pop_front();
+        }
+        self.genomes.push_back(sl.clone());
+    }
+
+    pub fn random(&self, rng: &mut Rng) -> Option<ShapeList> {
+        if self.genomes.len() == 0 {
+            return None;
+        }
+        let i = (rand(rng) * self.genomes.len() as f32) as usize;
+        return Some(self.genomes[i].clone());
+    }
+}
@@ -0,0 +1,115 @@
+// Post-run pruning: drop the shapes that barely contribute.
+
+use lisa::Lisa;
+
+#[derive(Debug, Clone)]
+pub struct PruneReport {
+    pub shapes_before: usize,
+    pub shapes_after: usize,
+    pub fitness_before: f64,
+    pub fitness_after: f64
+}
+
+impl PruneReport {
+    pub fn removed(&self) -> usize {
+        return self.shapes_before - self.shapes_after;
+    }
+
+    pub fn delta(&self) -> f64 {
+        return self.fitness_after - self.fitness_before;
+    }
+}
+
+/// Try removing each shape, top first as those are cheapest to
+/// re-render, and keep the removal if fitness stays within `threshold`
+/// (a fraction of the starting fitness) of where it started, so the
+/// total loss is bounded however many shapes go. Repeats until a full
+/// pass removes nothing.
+pub fn prune(lisa: &mut Lisa, threshold: f64) -> PruneReport {
+    let shapes_before = lisa.shapes.len();
+    let fitness_before = lisa.calculate_fitness();
+    let limit = fitness_before * (1. + threshold);
+    let mut current = fitness_before;
+
+    loop {
+        let mut removed_any = false;
+        let mut i = lisa.shapes.len();
+        while i > 0 {
+            i -= 1;
+            let shape = lisa.shapes.shapes.remove(i);
+            let fitness = lisa.calculate_fitness();
+            if fitness < limit {
+                current = fitness;
+                removed_any = true;
+                lisa.cache_from(i);
+            } else {
+                lisa.shapes.shapes.insert(i, shape);
+            }
+        }
+        if !removed_any {
+            break;
+        }
+    }
+
+    return PruneReport {
+        shapes_before: shapes_before,
+        shapes_after: lisa.shapes.len(),
+        fitness_before: fitness_before,
+        fitness_after: current
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::prune;
+    use lisa::Lisa;
+    use context::Context;
+    use shapes::{Shape, Rect};
+    use color::Color;
+    use std::sync::Arc;
+
+    fn rect(color: Color) -> Shape {
+        return Shape::Rect(Rect {
+            x: 0., y: 0., width: 1., height: 1.,
+            color: color,
+            blend: Default::default(),
+            steps: Default::default()
+        });
+    }
+
+    #[test]
+    fn drops_only_what_doesnt_help(){
+        let ctx = Arc::new(Context::sample());
+        let mut lisa = Lisa::new(ctx.clone());
+        lisa.shapes.background = Color::black();
+        let mut mean = ctx.mean_color.clone();
+        mean.opacity = 1.;
+        // Covers the black background with the target's mean colour
+        lisa.shapes.shapes.push(rect(mean));
+        // Fully transparent, changes nothing
+        lisa.shapes.shapes.push(rect(Color { r: 255., g: 0., b: 0., opacity: 0. }));
+
+        let report = prune(&mut lisa, 1e-9);
+        assert_eq!(report.removed(), 1);
+        assert_eq!(lisa.shapes.len(), 1);
+        assert_eq!(lisa.shapes.shapes[0].color().opacity, 1.);
+        assert!(report.delta() <= 0.);
+    }
+
+    #[test]
+    fn loss_doesnt_compound(){
+        let ctx = Arc::new(Context::sample());
+        let mut lisa = Lisa::new(ctx.clone());
+        lisa.shapes.background = Color::black();
+        // Each layer helps a little
+        let mut faint = ctx.mean_color.clone();
+        faint.opacity = 0.05;
+        for _ in 0..20 {
+            lisa.shapes.shapes.push(rect(faint.clone()));
+        }
+
+        let report = prune(&mut lisa, 0.05);
+        assert!(report.removed() > 0);
+        assert!(report.fitness_after <= report.fitness_before * 1.05);
+    }
+}
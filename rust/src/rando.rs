@@ -1,26 +1,91 @@
-extern crate rand as _rand;
-use rando::_rand::Rng;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-pub fn rand() -> f32 {
-	return _rand::thread_rng().gen_range(0.,1.);
+/// A small seedable generator (xorshift64*), so that a run can be
+/// repeated exactly from its seed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rng {
+    state: u64
 }
 
-pub fn randu8() -> u8 {
-	return _rand::thread_rng().gen_range(0,255);
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        // splitmix64 the seed so that small or zero seeds still give a
+        // well mixed, non-zero state
+        let mut z = seed.wrapping_add(0x9E3779B97F4A7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z = z ^ (z >> 31);
+        return Rng { state: if z == 0 { 1 } else { z } };
+    }
+
+    /// A seed from the clock, for when none is given
+    pub fn entropy_seed() -> u64 {
+        let t = SystemTime::now().duration_since(UNIX_EPOCH).expect("clock before 1970");
+        return t.as_secs().wrapping_mul(1_000_000_007) ^ t.subsec_nanos() as u64;
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        return self.state.wrapping_mul(0x2545F4914F6CDD1D);
+    }
+}
+
+/// Uniform in [0, 1)
+pub fn rand(rng: &mut Rng) -> f32 {
+	return (rng.next_u64() >> 40) as f32 / (1u64 << 24) as f32;
 }
 
-pub fn randu8f() -> f32 {
-	return _rand::thread_rng().gen_range(0,255) as f32;
+pub fn rand_between(rng: &mut Rng, min: f32, max: f32) -> f32 {
+	return min + rand(rng) * (max - min);
 }
 
-pub fn rand_color_adjust(c:f32, range: f32) -> f32 {
-	return (c + ((rand() - 0.5) * 256.0 * range)).min(255.).max(0.);
+pub fn randu8(rng: &mut Rng) -> u8 {
+	return (rand(rng) * 255.) as u8;
 }
 
-pub fn rand_adjust(p:f32, range: f32, min: f32, max:f32) -> f32 {
-    return (p + ((rand() - 0.5) * range)).min(max).max(min);
+pub fn randu8f(rng: &mut Rng) -> f32 {
+	return randu8(rng) as f32;
 }
 
-pub fn choose<T>(v: &mut Vec<T>) -> Option<&mut T> {
-    return _rand::thread_rng().choose_mut::<T>(v);
+/// Standard normal sample (Box-Muller)
+pub fn rand_gauss(rng: &mut Rng) -> f32 {
+    let u1 = rand(rng).max(1e-7);
+    let u2 = rand(rng);
+    return (-2. * u1.ln()).sqrt() * (2. * ::std::f32::consts::PI * u2).cos();
+}
+
+pub fn rand_color_adjust(rng: &mut Rng, c:f32, sigma: f32) -> f32 {
+	return (c + rand_gauss(rng) * 256.0 * sigma).min(255.).max(0.);
+}
+
+pub fn rand_adjust(rng: &mut Rng, p:f32, sigma: f32, min: f32, max:f32) -> f32 {
+    return (p + rand_gauss(rng) * sigma).min(max).max(min);
+}
+
+pub fn choose<'a, T>(rng: &mut Rng, v: &'a mut Vec<T>) -> Option<&'a mut T> {
+    if v.is_empty() {
+        return None;
+    }
+    let i = (rand(rng) * v.len() as f32) as usize;
+    return v.get_mut(i);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Rng, rand};
+
+    #[test]
+    fn same_seed_same_stream(){
+        let mut a = Rng::new(42);
+        let mut b = Rng::new(42);
+        let mut c = Rng::new(43);
+        let xs: Vec<f32> = (0..100).map(|_| rand(&mut a)).collect();
+        let ys: Vec<f32> = (0..100).map(|_| rand(&mut b)).collect();
+        let zs: Vec<f32> = (0..100).map(|_| rand(&mut c)).collect();
+        assert_eq!(xs, ys);
+        assert!(xs != zs);
+        assert!(xs.iter().all(|&x| x >= 0. && x < 1.));
+    }
 }
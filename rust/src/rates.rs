@@ -0,0 +1,276 @@
+// Mutation operator probabilities, either fixed from configuration or
+// adapted during the run towards the operators that get accepted.
+
+extern crate serde_json;
+
+use rando::{Rng, rand};
+use std::fs::File;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Every operator keeps at least this chance in adaptive mode
+const MIN_PROBABILITY: f32 = 0.02;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+    Add,
+    AddGuided,
+    Remove,
+    Swap,
+    Merge,
+    Crossover,
+    Insert,
+    Move,
+    Split,
+    Change
+}
+
+pub const OPERATORS: [Operator; 10] = [
+    Operator::Add,
+    Operator::AddGuided,
+    Operator::Remove,
+    Operator::Swap,
+    Operator::Merge,
+    Operator::Crossover,
+    Operator::Insert,
+    Operator::Move,
+    Operator::Split,
+    Operator::Change
+];
+
+impl Operator {
+    pub fn index(&self) -> usize {
+        return OPERATORS.iter().position(|o| o == self).unwrap();
+    }
+}
+
+/// Which gene of a shape gets changed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Gene {
+    Color,
+    Blend,
+    Geometry(usize)
+}
+
+/// Relative weights for mutating a shape's colour, blend mode or one
+/// of its (equally weighted) geometric parameters.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ShapeWeights {
+    pub color: f32,
+    pub blend: f32,
+    pub geometry: f32
+}
+
+impl Default for ShapeWeights {
+    fn default() -> ShapeWeights {
+        ShapeWeights { color: 0.35, blend: 0.05, geometry: 0.6 }
+    }
+}
+
+impl ShapeWeights {
+    pub fn pick(&self, geometry_genes: usize, rng: &mut Rng) -> Gene {
+        let r = rand(rng) * (self.color + self.blend + self.geometry);
+        if r < self.color {
+            return Gene::Color;
+        }
+        if r < self.color + self.blend {
+            return Gene::Blend;
+        }
+        let g = ((r - self.color - self.blend) / self.geometry * geometry_genes as f32) as usize;
+        return Gene::Geometry(g.min(geometry_genes - 1));
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MutationRates {
+    pub add: f32,
+    pub add_guided: f32,
+    pub remove: f32,
+    pub swap: f32,
+    pub merge: f32,
+    /// Breed with a recent fittest genome instead of mutating. Only
+    /// used by the single genome engines, the native engine breeds
+    /// from its population.
+    pub crossover: f32,
+    /// Insert a new shape a few layers below the top
+    pub insert: f32,
+    /// Move a shape up or down a few layers
+    pub move_depth: f32,
+    pub split: f32,
+    pub change: f32,
+    /// Chance that a change targets the last shape, which is cheapest
+    /// to re-render
+    pub last_shape: f32,
+    /// Chance that a change targets the background colour
+    pub background: f32,
+    pub rect: ShapeWeights,
+    pub triangle: ShapeWeights,
+    pub circle: ShapeWeights
+}
+
+impl Default for MutationRates {
+    fn default() -> MutationRates {
+        MutationRates {
+            add: 0.05,
+            add_guided: 0.05,
+            remove: 0.05,
+            swap: 0.05,
+            merge: 0.02,
+            crossover: 0.,
+            insert: 0.03,
+            move_depth: 0.03,
+            split: 0.02,
+            change: 0.8,
+            last_shape: 0.8,
+            background: 0.02,
+            rect: ShapeWeights { color: 0.55, blend: 0.05, geometry: 0.4 },
+            triangle: ShapeWeights::default(),
+            circle: ShapeWeights::default()
+        }
+    }
+}
+
+impl MutationRates {
+    pub fn from_file(path: &str) -> MutationRates {
+        let f = File::open(path).expect("failed to open mutation rates");
+        return serde_json::from_reader(f).expect("invalid mutation rates");
+    }
+
+    pub fn weight(&self, op: Operator) -> f32 {
+        match op {
+            Operator::Add => self.add,
+            Operator::AddGuided => self.add_guided,
+            Operator::Remove => self.remove,
+            Operator::Swap => self.swap,
+            Operator::Merge => self.merge,
+            Operator::Crossover => self.crossover,
+            Operator::Insert => self.insert,
+            Operator::Move => self.move_depth,
+            Operator::Split => self.split,
+            Operator::Change => self.change
+        }
+    }
+}
+
+/// Attempt and acceptance counts per operator, shared by all individuals.
+#[derive(Debug)]
+pub struct OperatorStats {
+    attempts: Vec<AtomicUsize>,
+    accepted: Vec<AtomicUsize>
+}
+
+impl Default for OperatorStats {
+    fn default() -> OperatorStats {
+        OperatorStats::new()
+    }
+}
+
+impl OperatorStats {
+    pub fn new() -> OperatorStats {
+        OperatorStats {
+            attempts: OPERATORS.iter().map(|_| AtomicUsize::new(0)).collect(),
+            accepted: OPERATORS.iter().map(|_| AtomicUsize::new(0)).collect()
+        }
+    }
+
+    pub fn attempt(&self, op: Operator) {
+        self.attempts[op.index()].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn attempts(&self, op: Operator) -> usize {
+        return self.attempts[op.index()].load(Ordering::Relaxed);
+    }
+
+    /// A child made with `op` replaced a parent or survived selection
+    pub fn accept(&self, op: Operator) {
+        self.accepted[op.index()].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn accepted(&self, op: Operator) -> usize {
+        return self.accepted[op.index()].load(Ordering::Relaxed);
+    }
+
+    /// Probability matching bandit: each enabled operator is chosen in
+    /// proportion to its smoothed acceptance rate, with a floor so none
+    /// are starved.
+    fn adaptive_weights(&self, rates: &MutationRates) -> Vec<f32> {
+        let enabled: Vec<bool> = OPERATORS.iter().map(|o| rates.weight(*o) > 0.).collect();
+        let n = enabled.iter().filter(|e| **e).count() as f32;
+        let success: Vec<f32> = OPERATORS.iter().zip(enabled.iter()).map(|(o, e)| {
+            if !e {
+                return 0.;
+            }
+            (self.accepted(*o) as f32 + 1.) / (self.attempts(*o) as f32 + 2.)
+        }).collect();
+        let total: f32 = success.iter().sum();
+        return success.iter().zip(enabled.iter()).map(|(s, e)| {
+            if !e {
+                return 0.;
+            }
+            MIN_PROBABILITY + (1. - n * MIN_PROBABILITY).max(0.) * s / total
+        }).collect();
+    }
+
+    fn weights(&self, rates: &MutationRates, adaptive: bool) -> Vec<f32> {
+        if adaptive {
+            return self.adaptive_weights(rates);
+        }
+        return OPERATORS.iter().map(|o| rates.weight(*o)).collect();
+    }
+
+    pub fn choose(&self, rates: &MutationRates, adaptive: bool, rng: &mut Rng) -> Operator {
+        let weights = self.weights(rates, adaptive);
+        let mut r = rand(rng) * weights.iter().sum::<f32>();
+        for (i, w) in weights.iter().enumerate() {
+            if r < *w {
+                return OPERATORS[i];
+            }
+            r -= *w;
+        }
+        return Operator::Change;
+    }
+
+    /// Current probability of each operator, for status output
+    pub fn probabilities(&self, rates: &MutationRates, adaptive: bool) -> Vec<f32> {
+        let weights = self.weights(rates, adaptive);
+        let total: f32 = weights.iter().sum();
+        return weights.iter().map(|w| w / total).collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(counts: &[(Operator, usize, usize)]) -> OperatorStats {
+        let stats = OperatorStats::default();
+        for &(op, attempts, accepted) in counts {
+            for _ in 0..attempts {
+                stats.attempt(op);
+            }
+            for _ in 0..accepted {
+                stats.accept(op);
+            }
+        }
+        return stats;
+    }
+
+    #[test]
+    fn adaptive_weights(){
+        let rates = MutationRates { remove: 0., ..MutationRates::default() };
+        let s = stats(&[(Operator::Change, 1000, 900), (Operator::Add, 1000, 0), (Operator::Remove, 10, 10)]);
+        let w = s.adaptive_weights(&rates);
+        assert!((w.iter().sum::<f32>() - 1.).abs() < 1e-5);
+        // Switched off, however well it did
+        assert_eq!(w[Operator::Remove.index()], 0.);
+        for op in OPERATORS.iter().filter(|o| rates.weight(**o) > 0.) {
+            assert!(w[op.index()] >= MIN_PROBABILITY);
+        }
+        assert!(w[Operator::Change.index()] > w[Operator::Swap.index()]);
+        // Never accepted, held at the floor
+        assert!(w[Operator::Add.index()] < MIN_PROBABILITY + 0.001);
+        let p = s.probabilities(&rates, true);
+        assert!(p.iter().zip(w.iter()).all(|(a, b)| (a - b).abs() < 1e-6));
+    }
+}
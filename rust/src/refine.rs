@@ -0,0 +1,135 @@
+// Coordinate descent over every shape parameter, for the last stretch
+// where random mutation rarely finds anything.
+
+use lisa::Lisa;
+use context::Context;
+
+#[derive(Debug, Clone)]
+pub struct RefineReport {
+    pub passes: usize,
+    pub improvements: usize,
+    pub fitness_before: f64,
+    pub fitness_after: f64
+}
+
+/// Which entries of `Shape::params` may move. Colour is fixed to the
+/// palette entry when one is in use, and opacity in opaque mode or when
+/// the palette sets it.
+pub fn tunable(len: usize, palette: bool, fixed_opacity: bool) -> Vec<usize> {
+    let geometry = len - 4;
+    let mut out: Vec<usize> = (0..geometry).collect();
+    if !palette {
+        out.extend(geometry..geometry + 3);
+    }
+    if !fixed_opacity {
+        out.push(geometry + 3);
+    }
+    return out;
+}
+
+/// Whether opacity is fixed for every shape in this context
+pub fn fixed_opacity(ctx: &Context) -> bool {
+    return ctx.opaque || ctx.palette_opacity.is_some();
+}
+
+/// Probe each parameter of each shape by ±delta, keeping any step that
+/// lowers the fitness and halving delta when neither direction helps,
+/// until it drops below `min_delta`. Repeats for up to `max_passes`
+/// passes or until a pass finds nothing.
+pub fn refine(lisa: &mut Lisa, delta: f32, min_delta: f32, max_passes: usize) -> RefineReport {
+    let palette = lisa.ctx.palette.is_some();
+    let fixed = fixed_opacity(&lisa.ctx);
+    let margin = lisa.ctx.margin;
+    let fitness_before = lisa.calculate_fitness();
+    let mut current = fitness_before;
+    let mut improvements = 0;
+    let mut passes = 0;
+
+    while passes < max_passes {
+        passes += 1;
+        let before_pass = improvements;
+
+        // Top first: probes there only re-render one shape over a
+        // cached canvas.
+        for i in (0..lisa.shapes.len()).rev() {
+            let mut params = lisa.shapes.shapes[i].params();
+            for &j in tunable(params.len(), palette, fixed).iter() {
+                let mut step = delta;
+                while step >= min_delta {
+                    let original = params[j];
+                    let mut improved = false;
+                    for &dir in [1., -1.].iter() {
+                        params[j] = original + dir * step;
+                        lisa.shapes.shapes[i].set_params(&params, margin);
+                        let fitness = lisa.calculate_fitness();
+                        if fitness < current {
+                            current = fitness;
+                            improved = true;
+                            break;
+                        }
+                    }
+                    if improved {
+                        // set_params may have clamped
+                        params = lisa.shapes.shapes[i].params();
+                        improvements += 1;
+                        lisa.cache_from(i);
+                    } else {
+                        params[j] = original;
+                        lisa.shapes.shapes[i].set_params(&params, margin);
+                        step /= 2.;
+                    }
+                }
+            }
+        }
+
+        println!("# Refine pass {}: {:.1} ({} improvements)", passes, current, improvements);
+        if improvements == before_pass {
+            break;
+        }
+    }
+
+    return RefineReport {
+        passes: passes,
+        improvements: improvements,
+        fitness_before: fitness_before,
+        fitness_after: current
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{tunable, refine};
+    use lisa::Lisa;
+    use context::Context;
+    use shapes::{Shape, Rect};
+    use color::Color;
+    use std::sync::Arc;
+
+    #[test]
+    fn tunable_respects_locks(){
+        assert_eq!(tunable(7, false, false), vec![0, 1, 2, 3, 4, 5, 6]);
+        assert_eq!(tunable(7, false, true), vec![0, 1, 2, 3, 4, 5]);
+        assert_eq!(tunable(7, true, true), vec![0, 1, 2]);
+        // A palette without a fixed opacity still lets opacity move
+        assert_eq!(tunable(7, true, false), vec![0, 1, 2, 6]);
+    }
+
+    #[test]
+    fn improves_and_stays_in_bounds(){
+        let ctx = Arc::new(Context::sample());
+        let mut lisa = Lisa::new(ctx);
+        lisa.shapes.background = Color::black();
+        lisa.shapes.shapes.push(Shape::Rect(Rect {
+            x: 0.3, y: 0.3, width: 0.2, height: 0.2,
+            color: Color { r: 20., g: 200., b: 20., opacity: 0.5 },
+            blend: Default::default(),
+            steps: Default::default()
+        }));
+        let report = refine(&mut lisa, 0.2, 0.01, 3);
+        assert!(report.improvements > 0);
+        assert!(report.fitness_after < report.fitness_before);
+        assert_eq!(report.fitness_after, lisa.calculate_fitness());
+        let p = lisa.shapes.shapes[0].params();
+        assert!(p[0] >= 0. && p[1] >= 0. && p[2] <= 1. && p[3] <= 1.);
+    }
+}
@@ -1,84 +1,386 @@
 use shapes::{Shape};
 use std::fmt::Write;
 use canvas::{Canvas};
-use rando::{rand, choose};
+use rando::{Rng, rand};
 use context::Context;
+use color::{Color, BlendMode};
+use steps::StepSizes;
+use palette::Palette;
+use guide;
+use colorsolve;
+use std::hash::{Hash, Hasher};
 
-#[derive(PartialEq, Eq, Hash, Debug, Clone, Serialize, Deserialize)]
+/// Shapes further apart than this, by `Shape::distance`, are too far
+/// apart to merge
+const MERGE_DISTANCE: f32 = 0.25;
+
+/// Where to cut a genome of length `b` to match a cut at `i` in one of
+/// length `a`. Children cut this way are never longer than the longer
+/// parent, or shorter than the shorter.
+fn matching_cut(i: usize, a: usize, b: usize) -> usize {
+    if a == 0 {
+        return 0;
+    }
+    return i * b / a;
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ShapeList {
-    pub shapes: Vec<Shape>
+    pub shapes: Vec<Shape>,
+    #[serde(default = "Color::black")]
+    pub background: Color,
+    /// Self-adapted like a shape's, but not part of the genome's identity
+    #[serde(default)]
+    pub background_steps: StepSizes
+}
+
+impl PartialEq for ShapeList {
+    fn eq(&self, other: &ShapeList) -> bool {
+        return self.shapes == other.shapes && self.background == other.background;
+    }
+}
+
+impl Eq for ShapeList {}
+
+impl Hash for ShapeList {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.shapes.hash(state);
+        self.background.hash(state);
+    }
 }
 
 impl ShapeList {
     pub fn new() -> ShapeList {
+        ShapeList::with_background(Color::black())
+    }
+
+    pub fn with_background(background: Color) -> ShapeList {
         ShapeList {
-            shapes: Vec::new()
+            shapes: Vec::new(),
+            background: background,
+            background_steps: StepSizes::default()
         }
     }
 
     pub fn slice(&self, i: usize) -> ShapeList{
         let n = (&self.shapes[0..i]).to_vec();
-        return ShapeList { shapes: n }
+        return ShapeList {
+            shapes: n,
+            background: self.background.clone(),
+            background_steps: self.background_steps.clone()
+        }
+    }
+
+    /// A blank canvas painted with the background gene
+    pub fn blank_canvas(&self, width: usize, height: usize, depth: usize) -> Canvas {
+        return Canvas::with_background(width, height, depth, &self.background);
     }
 
-    fn remove_random(&mut self) {
-        let i = (rand() * self.shapes.len() as f32) as usize;
+    fn remove_random(&mut self, rng: &mut Rng) {
+        let i = (rand(rng) * self.shapes.len() as f32) as usize;
         self.shapes.remove(i);
     }
 
-    pub fn add_random(&mut self, ctx: &Context) { 
-        self.shapes.push(Shape::random(ctx.use_triangles, ctx.use_circles, ctx.use_rectangles));
+    pub fn add_random(&mut self, ctx: &Context, rng: &mut Rng) { 
+        self.shapes.push(Shape::random(ctx, rng));
+    }
+
+    /// A shape where the canvas differs most from the target, sized to
+    /// the local error and coloured from the target beneath it.
+    fn guided_shape(ctx: &Context, canv: &Canvas, rng: &mut Rng) -> Shape {
+        let errors = guide::error_map(canv, &ctx.image);
+        return ShapeList::shape_for_errors(ctx, &errors, rng);
+    }
+
+    /// As `guided_shape`, from an error map already worked out
+    pub fn shape_for_errors(ctx: &Context, errors: &[f32], rng: &mut Rng) -> Shape {
+        let width = ctx.width as usize;
+        let height = ctx.height as usize;
+        let (x, y) = guide::sample(errors, width, rng);
+        let r = guide::blob_radius(errors, width, height, x, y);
+        let mut shape = Shape::random_at(ctx,
+                                         x as f32 / width as f32,
+                                         y as f32 / height as f32,
+                                         r as f32 / width as f32,
+                                         rng);
+
+        if let Some(mut c) = ctx.image.mean_color_under(&shape.mask(width, height)) {
+            if let Some(ref p) = ctx.palette {
+                c = p.colors[p.nearest(&c)].clone();
+            }
+            c.opacity = shape.color().opacity;
+            shape.set_color(c);
+        }
+        return shape;
+    }
+
+    pub fn add_guided(&mut self, ctx: &Context, canv: &Canvas, rng: &mut Rng) {
+        self.shapes.push(ShapeList::guided_shape(ctx, canv, rng));
+    }
+
+    /// A position in the stack (0 - len inclusive), usually within a few
+    /// layers of the top so the cache only has to re-render a little.
+    fn depth_near_end(&self, rng: &mut Rng) -> usize {
+        let offset = (-rand(rng).max(1e-6).ln() * 3.) as usize;
+        return self.shapes.len() - offset.min(self.shapes.len());
+    }
+
+    /// Insert a new shape below the top of the stack, so new detail can
+    /// slide under existing highlights. If `guided`, placed by the error
+    /// of the stack below the chosen depth.
+    pub fn insert_at_depth(&mut self, ctx: &Context, guided: bool, rng: &mut Rng) {
+        let i = self.depth_near_end(rng);
+        let shape = if guided {
+            let below = ctx.cache.canvas_for(&self.slice(i));
+            ShapeList::guided_shape(ctx, &below, rng)
+        } else {
+            Shape::random(ctx, rng)
+        };
+        self.shapes.insert(i, shape);
+    }
+
+    /// Move a shape up or down a few layers
+    pub fn move_to_depth(&mut self, rng: &mut Rng) {
+        let l = self.shapes.len();
+        if l < 2 {
+            return;
+        }
+        let i = self.depth_near_end(rng).min(l - 1);
+        let steps = 1 + (rand(rng) * 3.) as usize;
+        let j = if rand(rng) < 0.5 { i.saturating_sub(steps) } else { (i + steps).min(l - 1) };
+        let shape = self.shapes.remove(i);
+        self.shapes.insert(j, shape);
+    }
+
+    /// Split one of the larger shapes into two smaller ones of the same
+    /// kind, so coarse shapes can be refined keeping their colour.
+    pub fn split(&mut self, rng: &mut Rng) {
+        let l = self.shapes.len();
+        if l == 0 {
+            return;
+        }
+        // Largest of a few random picks
+        let mut i = (rand(rng) * l as f32) as usize;
+        for _ in 0..2 {
+            let j = (rand(rng) * l as f32) as usize;
+            if self.shapes[j].area() > self.shapes[i].area() {
+                i = j;
+            }
+        }
+        let (a, b) = self.shapes[i].split(rng);
+        self.shapes[i] = a;
+        self.shapes.insert(i + 1, b);
+    }
+
+    /// Replace a random shape and its most similar partner of the same
+    /// kind with a single shape combining the two, if they are close
+    /// enough. False if there was nothing to merge.
+    pub fn merge(&mut self, ctx: &Context, rng: &mut Rng) -> bool {
+        let l = self.shapes.len();
+        if l < 2 {
+            return false;
+        }
+        let i = (rand(rng) * l as f32) as usize;
+        let mut partner = None;
+        for j in 0..l {
+            if j == i {
+                continue;
+            }
+            if let Some(d) = self.shapes[i].distance(&self.shapes[j]) {
+                match partner {
+                    Some((_, best)) if best <= d => {},
+                    _ => partner = Some((j, d))
+                }
+            }
+        }
+        if let Some((j, d)) = partner {
+            if d > MERGE_DISTANCE {
+                return false;
+            }
+            if let Some(mut merged) = self.shapes[i].merge(&self.shapes[j]) {
+                if let Some(ref p) = ctx.palette {
+                    let c = p.snap(merged.color());
+                    merged.set_color(c);
+                }
+                let (lo, hi) = if i < j { (i, j) } else { (j, i) };
+                self.shapes[lo] = merged;
+                self.shapes.remove(hi);
+                return true;
+            }
+        }
+        return false;
+    }
+
+    /// Cut both parents once, at the same relative depth, and join the
+    /// head of `a` to the tail of `b`
+    pub fn crossover_one_point(a: &ShapeList, b: &ShapeList, rng: &mut Rng) -> ShapeList {
+        let i = (rand(rng) * (a.len() + 1) as f32) as usize;
+        let j = matching_cut(i, a.len(), b.len());
+        let mut child = a.slice(i);
+        child.shapes.extend_from_slice(&b.shapes[j..]);
+        return child;
+    }
+
+    /// Replace a run of `a`'s shapes with the run from `b` at the same
+    /// relative depth
+    pub fn crossover_two_point(a: &ShapeList, b: &ShapeList, rng: &mut Rng) -> ShapeList {
+        let x = (rand(rng) * (a.len() + 1) as f32) as usize;
+        let y = (rand(rng) * (a.len() + 1) as f32) as usize;
+        let (i1, i2) = if x < y { (x, y) } else { (y, x) };
+        let j1 = matching_cut(i1, a.len(), b.len());
+        let j2 = matching_cut(i2, a.len(), b.len());
+        let mut child = a.slice(i1);
+        child.shapes.extend_from_slice(&b.shapes[j1..j2]);
+        child.shapes.extend_from_slice(&a.shapes[i2..]);
+        return child;
+    }
+
+    /// Take the shapes centred inside a random region of the image from
+    /// `a` and those outside it from `b`, interleaved by relative depth.
+    pub fn crossover_spatial(a: &ShapeList, b: &ShapeList, rng: &mut Rng) -> ShapeList {
+        let x1 = rand(rng);
+        let x2 = rand(rng);
+        let y1 = rand(rng);
+        let y2 = rand(rng);
+        let inside = |s: &Shape| {
+            let (x, y) = s.centre();
+            x >= x1.min(x2) && x <= x1.max(x2) && y >= y1.min(y2) && y <= y1.max(y2)
+        };
+
+        let mut layered: Vec<(f32, &Shape)> = Vec::new();
+        for (i, s) in a.shapes.iter().enumerate() {
+            if inside(s) {
+                layered.push((i as f32 / a.len() as f32, s));
+            }
+        }
+        for (i, s) in b.shapes.iter().enumerate() {
+            if !inside(s) {
+                layered.push((i as f32 / b.len() as f32, s));
+            }
+        }
+        layered.sort_by(|p, q| p.0.partial_cmp(&q.0).unwrap());
+
+        let mut child = a.slice(0);
+        child.shapes = layered.into_iter().map(|(_, s)| s.clone()).collect();
+        return child;
+    }
+
+    /// A child of `a` and `b` from a randomly chosen crossover operator
+    pub fn crossover(a: &ShapeList, b: &ShapeList, rng: &mut Rng) -> ShapeList {
+        match (rand(rng) * 3.) as u8 {
+            0 => ShapeList::crossover_one_point(a, b, rng),
+            1 => ShapeList::crossover_two_point(a, b, rng),
+            _ => ShapeList::crossover_spatial(a, b, rng)
+        }
     }
 
-    pub fn remove_shape(&mut self) {
+    pub fn remove_shape(&mut self, rng: &mut Rng) {
         if self.shapes.len() > 1 {
-            self.remove_random();
+            self.remove_random(rng);
         }
     }
     
-    pub fn swap(&mut self){
+    pub fn swap(&mut self, rng: &mut Rng){
         if self.len() > 2 {
-            let i = (rand() * self.shapes.len() as f32) as usize;
-            let i2 = (rand() * self.shapes.len() as f32) as usize;
+            let i = (rand(rng) * self.shapes.len() as f32) as usize;
+            let i2 = (rand(rng) * self.shapes.len() as f32) as usize;
             if i != i2 {
                 self.shapes.swap(i, i2);
             }
         }
     }
 
-    pub fn mutate(&mut self) {
-        match (rand() * 100.) as u8 {
-            0...80 => {
-                // Bias end mutations as they're cheaper
-                let l = self.shapes.len();
-                if l > 0 {
-                    self.shapes[l - 1].mutate();
-                }
-            },
-            80...100 => {
-                match choose(&mut self.shapes) {
-                    Some(m) => { m.mutate(); }
-                    None => {}
+    /// Mutate a shape or the background, returning the index of the
+    /// shape that changed.
+    pub fn mutate(&mut self, ctx: &Context, rng: &mut Rng) -> Option<usize> {
+        let l = self.shapes.len();
+        if rand(rng) < ctx.rates.background {
+            let mut bg = match ctx.palette {
+                Some(ref p) => p.mutate(&self.background, rng),
+                None => {
+                    self.background_steps.adapt_color(rng);
+                    self.background.mutate(&self.background_steps, rng)
                 }
-            },
-            _ => panic!()
+            };
+            // The background is always opaque
+            bg.opacity = 1.;
+            self.background = bg;
+            return None;
+        }
+        if l == 0 {
+            return None;
+        }
+        // Bias end mutations as they're cheaper
+        let i = if rand(rng) < ctx.rates.last_shape { l - 1 } else { (rand(rng) * l as f32) as usize };
+        self.shapes[i].mutate(ctx, rng);
+        return Some(i);
+    }
+
+    /// Replace the colour of shape `i` with the least squares best fit
+    /// over the pixels it covers, given the canvas beneath it.
+    pub fn solve_color(&mut self, ctx: &Context, i: usize, below: &Canvas) {
+        let shape = &mut self.shapes[i];
+        if shape.blend() != BlendMode::Normal {
+            return;
+        }
+        let opacity = if ctx.opaque { Some(1.) } else { ctx.palette.as_ref().and(ctx.palette_opacity) };
+        let mask = shape.mask(ctx.width as usize, ctx.height as usize);
+        let current = shape.color().opacity;
+        if let Some(mut c) = colorsolve::solve(below, &ctx.image, &mask, opacity, current) {
+            if let Some(ref p) = ctx.palette {
+                c = p.snap(&c);
+            }
+            shape.set_color(c);
+        }
+    }
+
+
+    /// Mean positional step size over all shapes
+    pub fn mean_step(&self) -> f32 {
+        if self.shapes.len() == 0 {
+            return StepSizes::default().position;
+        }
+        let total: f32 = self.shapes.iter().map(|s| s.steps().position).sum();
+        return total / self.shapes.len() as f32;
+    }
+
+    /// Scale every shape's step sizes, to shake a stuck genome loose
+    pub fn scale_steps(&mut self, factor: f32) {
+        for s in self.shapes.iter_mut() {
+            s.steps_mut().scale(factor);
         }
     }
 
+    /// Drop the top `k` shapes
+    pub fn drop_top(&mut self, k: usize) {
+        let keep = self.shapes.len().saturating_sub(k);
+        self.shapes.truncate(keep);
+    }
+
+    /// How many shapes from the bottom are the same in both
+    pub fn common_prefix(&self, other: &ShapeList) -> usize {
+        if self.background != other.background {
+            return 0;
+        }
+        return self.shapes.iter().zip(other.shapes.iter()).take_while(|&(a, b)| a == b).count();
+    }
 
     pub fn len(&self) -> usize {
         return self.shapes.len();
     }
 
-	pub fn svg(&self, width: usize, height: usize, depth: usize) -> String {
+	pub fn svg(&self, width: usize, height: usize, depth: usize, palette: Option<&Palette>) -> String {
 		let mut out = String::new();
 		let mut contents = String::new();
+        if let Some(p) = palette {
+            contents.push_str(&p.svg_style());
+        }
 		for c in &self.shapes{
-			contents.push_str(&c.svg(width, height, depth));
+			contents.push_str(&c.svg(width, height, depth, palette));
 		}
-        let svgprelude = "svg xmlns='http://www.w3.org/2000/svg' style='background-color: #000;' ";
-		write!(&mut out, "<{} width='{}' height='{}' >{}</svg>",
-                svgprelude, width, height, contents)
+        let svgprelude = "svg xmlns='http://www.w3.org/2000/svg'";
+		write!(&mut out, "<{} style='background-color: {};' width='{}' height='{}' >{}</svg>",
+                svgprelude, self.background.svg(depth), width, height, contents)
                 .expect("String concat failed");
 		return out;
 	}
@@ -101,3 +403,169 @@ impl ShapeList {
         return out;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::ShapeList;
+    use shapes::Shape;
+    use color::Color;
+    use context::Context;
+    use palette::Palette;
+    use rando::Rng;
+
+    /// Circles spread across the image, told apart by their red channel
+    fn genome(n: usize, red: f32) -> ShapeList {
+        let mut sl = ShapeList::new();
+        for i in 0..n {
+            sl.shapes.push(Shape::circle((i as f32 + 0.5) / n as f32, (i as f32 * 0.37) % 1., 0.1,
+                                         Color { r: red, g: i as f32, b: 0., opacity: 0.5 }));
+        }
+        return sl;
+    }
+
+    fn joined(parts: &[&[Shape]]) -> Vec<Shape> {
+        return parts.iter().flat_map(|p| p.iter().cloned()).collect();
+    }
+
+    #[test]
+    fn one_point_crossover(){
+        let (a, b) = (genome(8, 10.), genome(6, 20.));
+        let mut rng = Rng::new(3);
+        for _ in 0..50 {
+            let child = ShapeList::crossover_one_point(&a, &b, &mut rng);
+            assert!((0..a.len() + 1).any(|i| (0..b.len() + 1).any(|j|
+                child.shapes == joined(&[&a.shapes[..i], &b.shapes[j..]]))));
+        }
+    }
+
+    #[test]
+    fn two_point_crossover(){
+        let (a, b) = (genome(6, 10.), genome(5, 20.));
+        let mut rng = Rng::new(4);
+        let mut spliced = false;
+        for _ in 0..50 {
+            let child = ShapeList::crossover_two_point(&a, &b, &mut rng);
+            // A head and a tail of a around a run from b
+            let found = (0..a.len() + 1).any(|i1| (i1..a.len() + 1).any(|i2|
+                (0..b.len() + 1).any(|j1| (j1..b.len() + 1).any(|j2|
+                    child.shapes == joined(&[&a.shapes[..i1], &b.shapes[j1..j2], &a.shapes[i2..]])))));
+            assert!(found);
+            spliced = spliced || child.shapes.iter().any(|s| s.color().r == 20.);
+        }
+        assert!(spliced);
+    }
+
+    #[test]
+    fn crossover_length_between_parents(){
+        let mut rng = Rng::new(10);
+        for &(la, lb) in [(8, 3), (3, 8), (5, 5), (0, 4), (4, 0), (1, 7)].iter() {
+            let (a, b) = (genome(la, 10.), genome(lb, 20.));
+            for _ in 0..50 {
+                for child in [ShapeList::crossover_one_point(&a, &b, &mut rng),
+                              ShapeList::crossover_two_point(&a, &b, &mut rng)].iter() {
+                    assert!(child.len() >= la.min(lb) && child.len() <= la.max(lb));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn spatial_crossover(){
+        // Same geometry in both, so each layer comes from exactly one
+        let (a, b) = (genome(10, 10.), genome(10, 20.));
+        let mut rng = Rng::new(5);
+        let mut mixed = false;
+        for _ in 0..50 {
+            let child = ShapeList::crossover_spatial(&a, &b, &mut rng);
+            assert_eq!(child.len(), a.len());
+            for (k, s) in child.shapes.iter().enumerate() {
+                assert!(*s == a.shapes[k] || *s == b.shapes[k]);
+            }
+            let from_a = child.shapes.iter().filter(|s| s.color().r == 10.).count();
+            mixed = mixed || (from_a > 0 && from_a < a.len());
+        }
+        assert!(mixed);
+    }
+
+    #[test]
+    fn insert_at_depth(){
+        let ctx = Context::sample();
+        let mut rng = Rng::new(6);
+        let mut near_top = 0;
+        for n in 0..100 {
+            let before = genome(12, 10.);
+            let mut sl = before.clone();
+            sl.insert_at_depth(&ctx, n % 2 == 0, &mut rng);
+            assert_eq!(sl.len(), before.len() + 1);
+            // Everything else keeps its order
+            let i = (0..sl.len()).find(|&i| i == before.len() || sl.shapes[i] != before.shapes[i]).unwrap();
+            let mut rest = sl.shapes.clone();
+            rest.remove(i);
+            assert!(rest == before.shapes);
+            if i + 3 >= before.len() {
+                near_top += 1;
+            }
+        }
+        assert!(near_top > 50);
+    }
+
+    #[test]
+    fn move_to_depth(){
+        let mut rng = Rng::new(7);
+        for _ in 0..100 {
+            let before = genome(12, 10.);
+            let mut sl = before.clone();
+            sl.move_to_depth(&mut rng);
+            assert_eq!(sl.len(), before.len());
+            // One shape moved at most three layers, the rest in order
+            let moved: Vec<usize> = (0..sl.len()).filter(|&i| sl.shapes[i] != before.shapes[i]).collect();
+            if moved.is_empty() {
+                continue;
+            }
+            let (lo, hi) = (moved[0], moved[moved.len() - 1]);
+            assert!(hi - lo <= 3);
+            let up = sl.shapes[hi] == before.shapes[lo];
+            let down = sl.shapes[lo] == before.shapes[hi];
+            assert!(up || down);
+        }
+    }
+
+    #[test]
+    fn background_mutation(){
+        let mut ctx = Context::sample();
+        ctx.rates.background = 1.;
+        let mut rng = Rng::new(8);
+        let mut sl = genome(3, 10.);
+        for _ in 0..20 {
+            assert_eq!(sl.mutate(&ctx, &mut rng), None);
+            assert_eq!(sl.background.opacity, 1.);
+        }
+        // Adapted as it goes
+        assert!(sl.background_steps != Default::default());
+
+        ctx.palette = Some(Palette::parse("#f00\n#0f0\n#00f\n").unwrap());
+        for _ in 0..20 {
+            sl.mutate(&ctx, &mut rng);
+            assert!(ctx.palette.as_ref().unwrap().index_of(&sl.background).is_some());
+        }
+    }
+
+    #[test]
+    fn merges_only_neighbours(){
+        let mut ctx = Context::sample();
+        let mut rng = Rng::new(9);
+        let red = Color { r: 200., g: 0., b: 0., opacity: 0.5 };
+        let mut sl = ShapeList::new();
+        sl.shapes.push(Shape::circle(0.1, 0.1, 0.1, red.clone()));
+        sl.shapes.push(Shape::circle(0.9, 0.9, 0.1, red.clone()));
+        assert!(!sl.merge(&ctx, &mut rng));
+        assert_eq!(sl.len(), 2);
+
+        sl.shapes[1] = Shape::circle(0.15, 0.1, 0.1, Color { r: 180., g: 0., b: 0., opacity: 0.5 });
+        ctx.palette = Some(Palette::parse("#c80000\n#0000c8\n").unwrap());
+        assert!(sl.merge(&ctx, &mut rng));
+        assert_eq!(sl.len(), 1);
+        // Mixed, then back on the palette
+        assert_eq!(*sl.shapes[0].color(), red);
+    }
+}
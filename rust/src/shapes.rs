@@ -1,17 +1,69 @@
 use canvas::{Canvas};
-use rando::{rand, rand_adjust, randu8f};
-use color::Color;
+use rando::{Rng, rand, rand_adjust, rand_between, randu8f};
+use palette::Palette;
+use rates::Gene;
+use color::{Color, BlendMode};
 use std::fmt::Write;
 use std::cmp::{min, max};
 use std::hash::{Hash, Hasher};
+use context::Context;
+use steps::StepSizes;
 
 pub trait ShapeBehaviour {
-    fn mutate(&mut self);
-    fn svg(&self, width: usize, height: usize, depth: usize) -> String;
+    fn mutate(&mut self, ctx: &Context, rng: &mut Rng);
+    fn svg(&self, width: usize, height: usize, depth: usize, palette: Option<&Palette>) -> String;
     fn to_string(&self) -> String;
     fn draw_onto(&self, &mut Canvas);
 }
 
+fn random_color(ctx: &Context, rng: &mut Rng) -> Color {
+    let opacity = if ctx.opaque { 1. } else { rand(rng) };
+    match ctx.palette {
+        Some(ref p) => {
+            let mut c = p.random(rng);
+            c.opacity = if ctx.opaque { 1. } else { ctx.palette_opacity.unwrap_or(opacity) };
+            return c;
+        },
+        None => Color {
+            r: randu8f(rng),
+            g: randu8f(rng),
+            b: randu8f(rng),
+            opacity: opacity
+        }
+    }
+}
+
+fn mutate_color(color: &Color, steps: &mut StepSizes, ctx: &Context, rng: &mut Rng) -> Color {
+    steps.adapt_color(rng);
+    let fixed_opacity = ctx.opaque || ctx.palette_opacity.is_some();
+    match ctx.palette {
+        // With a palette the hue can only jump between inks
+        Some(ref p) if fixed_opacity || rand(rng) < 0.5 => p.mutate(color, rng),
+        Some(_) => {
+            let mut c = color.clone();
+            c.opacity = rand_adjust(rng, c.opacity, steps.opacity, 0., 1.);
+            return c;
+        },
+        None if ctx.opaque => {
+            let mut c = color.mutate(steps, rng);
+            c.opacity = 1.;
+            return c;
+        },
+        None => color.mutate(steps, rng)
+    }
+}
+
+/// Fill attributes, referencing the palette classes where possible
+fn fill(color: &Color, depth: usize, palette: Option<&Palette>) -> String {
+    if let Some(i) = palette.and_then(|p| p.index_of(color)) {
+        if color.opacity >= 1. {
+            return format!("class='p{}'", i);
+        }
+        return format!("class='p{}' fill-opacity='{:.4}'", i, color.opacity);
+    }
+    return format!("fill='{}'", color.svg(depth));
+}
+
 #[derive(PartialEq, Hash, Eq, Debug, Clone, Serialize, Deserialize)]
 pub enum Shape {
     Circle(Circle),
@@ -20,31 +72,147 @@ pub enum Shape {
 }
 
 impl Shape {
-    pub fn random(triangles: bool, circles: bool, rects: bool) -> Shape {
-        match (rand() * 10.) as u8 {
-            0...4 if circles => { return Shape::Circle(Circle::random()) },
-            0...7 if triangles => { return Shape::Triangle(Triangle::random()) },
-            0...10 if rects => { return Shape::Rect(Rect::random()) },
-            0...10 if triangles => { return Shape::Triangle(Triangle::random()) },
-            0...10 if circles => { return Shape::Circle(Circle::random()) },
+    pub fn random(ctx: &Context, rng: &mut Rng) -> Shape {
+        let triangles = ctx.use_triangles;
+        let circles = ctx.use_circles;
+        let rects = ctx.use_rectangles;
+        match (rand(rng) * 10.) as u8 {
+            0...4 if circles => { return Shape::Circle(Circle::random(ctx, rng)) },
+            0...7 if triangles => { return Shape::Triangle(Triangle::random(ctx, rng)) },
+            0...10 if rects => { return Shape::Rect(Rect::random(ctx, rng)) },
+            0...10 if triangles => { return Shape::Triangle(Triangle::random(ctx, rng)) },
+            0...10 if circles => { return Shape::Circle(Circle::random(ctx, rng)) },
             _ => panic!("Unknown shape")
         }
     
     }
 
-    pub fn mutate(&mut self) {
+    /// A random shape centred on (x, y) with roughly the given radius,
+    /// all as fractions of the image width
+    pub fn random_at(ctx: &Context, x: f32, y: f32, size: f32, rng: &mut Rng) -> Shape {
+        let mut s = Shape::random(ctx, rng);
+        match s {
+            Shape::Triangle(ref mut t) => {
+                t.x1 = x + size * (rand(rng) * 2. - 1.);
+                t.y1 = y + size * (rand(rng) * 2. - 1.);
+                t.x2 = x + size * (rand(rng) * 2. - 1.);
+                t.y2 = y + size * (rand(rng) * 2. - 1.);
+                t.x3 = x + size * (rand(rng) * 2. - 1.);
+                t.y3 = y + size * (rand(rng) * 2. - 1.);
+            },
+            Shape::Rect(ref mut r) => {
+                r.x = x - size;
+                r.y = y - size;
+                r.width = size * 2.;
+                r.height = size * 2.;
+            },
+            Shape::Circle(ref mut c) => {
+                c.x = x;
+                c.y = y;
+                c.rad = size.max(0.01);
+            }
+        }
+        return s;
+    }
+
+    /// Rough centre of the shape, as fractions of the image
+    pub fn centre(&self) -> (f32, f32) {
         match self {
-            &mut Shape::Triangle(ref mut t) => t.mutate(),
-            &mut Shape::Rect(ref mut r) => r.mutate(),
-            &mut Shape::Circle(ref mut c) => c.mutate()
+            &Shape::Triangle(ref t) => ((t.x1 + t.x2 + t.x3) / 3., (t.y1 + t.y2 + t.y3) / 3.),
+            &Shape::Rect(ref r) => (r.x + r.width / 2., r.y + r.height / 2.),
+            &Shape::Circle(ref c) => (c.x, c.y)
         }
     }
 
-    pub fn svg(&self, width: usize, height: usize, depth: usize) -> String{
+    pub fn area(&self) -> f32 {
         match self {
-            &Shape::Triangle(ref t) => t.svg(width, height, depth),
-            &Shape::Rect(ref t) => t.svg(width, height, depth),
-            &Shape::Circle(ref c) => c.svg(width, height, depth)
+            &Shape::Triangle(ref t) => t.area(),
+            &Shape::Rect(ref r) => r.width * r.height,
+            &Shape::Circle(ref c) => ::std::f32::consts::PI * c.rad * c.rad
+        }
+    }
+
+    /// How different two shapes of the same kind are, in geometry and
+    /// colour. None for shapes of different kinds.
+    pub fn distance(&self, other: &Shape) -> Option<f32> {
+        match (self, other) {
+            (&Shape::Triangle(ref a), &Shape::Triangle(ref b)) => Some(a.distance(b)),
+            (&Shape::Rect(ref a), &Shape::Rect(ref b)) => Some(a.distance(b)),
+            (&Shape::Circle(ref a), &Shape::Circle(ref b)) => Some(a.distance(b)),
+            _ => None
+        }
+    }
+
+    /// Combine two shapes of the same kind into one
+    pub fn merge(&self, other: &Shape) -> Option<Shape> {
+        match (self, other) {
+            (&Shape::Triangle(ref a), &Shape::Triangle(ref b)) => Some(Shape::Triangle(a.merge(b))),
+            (&Shape::Rect(ref a), &Shape::Rect(ref b)) => Some(Shape::Rect(a.merge(b))),
+            (&Shape::Circle(ref a), &Shape::Circle(ref b)) => Some(Shape::Circle(a.merge(b))),
+            _ => None
+        }
+    }
+
+    /// Two smaller shapes of the same kind covering roughly this one
+    pub fn split(&self, rng: &mut Rng) -> (Shape, Shape) {
+        match self {
+            &Shape::Triangle(ref t) => { let (a, b) = t.split(); (Shape::Triangle(a), Shape::Triangle(b)) },
+            &Shape::Rect(ref r) => { let (a, b) = r.split(); (Shape::Rect(a), Shape::Rect(b)) },
+            &Shape::Circle(ref c) => { let (a, b) = c.split(rng); (Shape::Circle(a), Shape::Circle(b)) }
+        }
+    }
+
+    pub fn color(&self) -> &Color {
+        match self {
+            &Shape::Triangle(ref t) => &t.color,
+            &Shape::Rect(ref r) => &r.color,
+            &Shape::Circle(ref c) => &c.color
+        }
+    }
+
+    pub fn set_color(&mut self, color: Color) {
+        match self {
+            &mut Shape::Triangle(ref mut t) => t.color = color,
+            &mut Shape::Rect(ref mut r) => r.color = color,
+            &mut Shape::Circle(ref mut c) => c.color = color
+        }
+    }
+
+    pub fn blend(&self) -> BlendMode {
+        match self {
+            &Shape::Triangle(ref t) => t.blend,
+            &Shape::Rect(ref r) => r.blend,
+            &Shape::Circle(ref c) => c.blend
+        }
+    }
+
+    /// The pixels this shape covers, as a depth 1 canvas
+    pub fn mask(&self, width: usize, height: usize) -> Canvas {
+        let mut m = Canvas::new(width, height, 1);
+        let mut solid = self.clone();
+        solid.set_color(Color { r: 255., g: 255., b: 255., opacity: 1. });
+        match solid {
+            Shape::Triangle(ref mut t) => t.blend = BlendMode::Normal,
+            Shape::Rect(ref mut r) => r.blend = BlendMode::Normal,
+            Shape::Circle(ref mut c) => c.blend = BlendMode::Normal
+        }
+        solid.draw_onto(&mut m);
+        return m;
+    }
+
+    pub fn mutate(&mut self, ctx: &Context, rng: &mut Rng) {
+        match self {
+            &mut Shape::Triangle(ref mut t) => t.mutate(ctx, rng),
+            &mut Shape::Rect(ref mut r) => r.mutate(ctx, rng),
+            &mut Shape::Circle(ref mut c) => c.mutate(ctx, rng)
+        }
+    }
+
+    pub fn svg(&self, width: usize, height: usize, depth: usize, palette: Option<&Palette>) -> String{
+        match self {
+            &Shape::Triangle(ref t) => t.svg(width, height, depth, palette),
+            &Shape::Rect(ref t) => t.svg(width, height, depth, palette),
+            &Shape::Circle(ref c) => c.svg(width, height, depth, palette)
         }
     }
 
@@ -56,6 +224,75 @@ impl Shape {
         }
     }
 
+    /// The continuous genes as one vector: geometry as fractions of
+    /// the image, then the colour from `Color::params`
+    pub fn params(&self) -> Vec<f32> {
+        let mut p = match self {
+            &Shape::Triangle(ref t) => vec![t.x1, t.y1, t.x2, t.y2, t.x3, t.y3],
+            &Shape::Rect(ref r) => vec![r.x, r.y, r.width, r.height],
+            &Shape::Circle(ref c) => vec![c.x, c.y, c.rad]
+        };
+        p.extend_from_slice(&self.color().params());
+        return p;
+    }
+
+    /// The valid range of each entry of `params`, with positions allowed
+    /// within `margin` of the image and sizes up to its width plus both
+    /// margins
+    pub fn param_bounds(&self, margin: f32) -> Vec<(f32, f32)> {
+        let pos = (-margin, 1. + margin);
+        let size = |min: f32| (min, 1. + 2. * margin);
+        let mut b = match self {
+            &Shape::Triangle(_) => vec![pos; 6],
+            &Shape::Rect(_) => vec![pos, pos, size(0.), size(0.)],
+            &Shape::Circle(_) => vec![pos, pos, size(0.01)]
+        };
+        b.extend_from_slice(&[(0., 1.); 4]);
+        return b;
+    }
+
+    /// Inverse of `params`, clamping each entry to `param_bounds`
+    pub fn set_params(&mut self, p: &[f32], margin: f32) {
+        let p: Vec<f32> = p.iter().zip(self.param_bounds(margin))
+            .map(|(v, (lo, hi))| v.max(lo).min(hi)).collect();
+        let color = Color::from_params(&p[p.len() - 4..]);
+        match self {
+            &mut Shape::Triangle(ref mut t) => {
+                t.x1 = p[0]; t.y1 = p[1];
+                t.x2 = p[2]; t.y2 = p[3];
+                t.x3 = p[4]; t.y3 = p[5];
+            },
+            &mut Shape::Rect(ref mut r) => {
+                r.x = p[0];
+                r.y = p[1];
+                r.width = p[2];
+                r.height = p[3];
+            },
+            &mut Shape::Circle(ref mut c) => {
+                c.x = p[0];
+                c.y = p[1];
+                c.rad = p[2];
+            }
+        }
+        self.set_color(color);
+    }
+
+    pub fn steps(&self) -> &StepSizes {
+        match self {
+            &Shape::Triangle(ref t) => &t.steps,
+            &Shape::Rect(ref r) => &r.steps,
+            &Shape::Circle(ref c) => &c.steps
+        }
+    }
+
+    pub fn steps_mut(&mut self) -> &mut StepSizes {
+        match self {
+            &mut Shape::Triangle(ref mut t) => &mut t.steps,
+            &mut Shape::Rect(ref mut r) => &mut r.steps,
+            &mut Shape::Circle(ref mut c) => &mut c.steps
+        }
+    }
+
     #[inline]
     pub fn draw_onto(&self, canv: &mut Canvas) {
         match self {
@@ -66,41 +303,101 @@ impl Shape {
     }
 }
 
+#[cfg(test)]
+impl Shape {
+    /// A plain circle, for tests
+    pub fn circle(x: f32, y: f32, rad: f32, color: Color) -> Shape {
+        return Shape::Circle(Circle {
+            x: x, y: y, rad: rad,
+            color: color,
+            blend: BlendMode::Normal,
+            steps: StepSizes::default()
+        });
+    }
+}
+
 #[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
 pub struct Rect {
     pub x: f32,
     pub y: f32,
     pub width: f32,
     pub height: f32,
-    pub color: Color
+    pub color: Color,
+    #[serde(default)]
+    pub blend: BlendMode,
+    #[serde(default)]
+    pub steps: StepSizes
 }
 
 impl Rect {
-    pub fn random() -> Rect {
+    pub fn random(ctx: &Context, rng: &mut Rng) -> Rect {
         Rect {
-            x: rand(),
-            y: rand(),
-            width: rand(),
-            height: rand(),
-            color: Color {
-                r: randu8f(),
-                g: randu8f(),
-                b: randu8f(),
-                opacity: rand()
-            }
+            x: rand_between(rng, -ctx.margin, 1. + ctx.margin),
+            y: rand_between(rng, -ctx.margin, 1. + ctx.margin),
+            width: rand(rng),
+            height: rand(rng),
+            color: random_color(ctx, rng),
+            blend: BlendMode::Normal,
+            steps: StepSizes::default()
         }
     }
 
+    pub fn distance(&self, d: &Rect) -> f32 {
+        return (self.x - d.x).abs() + (self.y - d.y).abs() +
+               (self.width - d.width).abs() + (self.height - d.height).abs() +
+               self.color.distance(&d.color);
+    }
+
+    /// The bounding box of both, so adjacent rects become one covering
+    /// the pair, with the colours mixed by area.
+    pub fn merge(&self, d: &Rect) -> Rect {
+        let x = self.x.min(d.x);
+        let y = self.y.min(d.y);
+        let x2 = (self.x + self.width).max(d.x + d.width);
+        let y2 = (self.y + self.height).max(d.y + d.height);
+        let a1 = self.width * self.height;
+        let a2 = d.width * d.height;
+        Rect {
+            x: x,
+            y: y,
+            width: x2 - x,
+            height: y2 - y,
+            color: self.color.mix(&d.color, a2 / (a1 + a2).max(1e-6)),
+            blend: self.blend,
+            steps: self.steps.clone()
+        }
+    }
+
+    /// Cut in half across the longer side
+    pub fn split(&self) -> (Rect, Rect) {
+        let mut a = self.clone();
+        let mut b = self.clone();
+        if self.width >= self.height {
+            a.width = self.width / 2.;
+            b.width = self.width / 2.;
+            b.x = self.x + a.width;
+        } else {
+            a.height = self.height / 2.;
+            b.height = self.height / 2.;
+            b.y = self.y + a.height;
+        }
+        return (a, b);
+    }
 }
 
 impl ShapeBehaviour for Rect {
-    fn mutate(&mut self) {
-        match (rand() * 100.) as u8 {
-            0...60 => self.color = self.color.mutate(),
-            60...70 => self.x = rand_adjust(self.x, 0.5, 0., 1.0),
-            70...80 => self.y = rand_adjust(self.y, 0.5, 0., 1.0),
-            80...90 => self.width = rand_adjust(self.width, 0.5, 0., 1.0),
-            90...100 => self.height = rand_adjust(self.height, 0.5, 0., 1.0),
+    fn mutate(&mut self, ctx: &Context, rng: &mut Rng) {
+        let lo = -ctx.margin;
+        let hi = 1. + ctx.margin;
+        // Wide enough to reach across the image from outside it
+        let size = 1. + 2. * ctx.margin;
+        match ctx.rates.rect.pick(4, rng) {
+            Gene::Color => self.color = mutate_color(&self.color, &mut self.steps, ctx, rng),
+            Gene::Blend => self.blend = BlendMode::random(rng),
+            Gene::Geometry(0) => self.x = self.steps.perturb_position(self.x, lo, hi, rng),
+            Gene::Geometry(1) => self.y = self.steps.perturb_position(self.y, lo, hi, rng),
+            Gene::Geometry(2) => self.width = self.steps.perturb_position(self.width, 0., size, rng),
+            Gene::Geometry(3) => self.height = self.steps.perturb_position(self.height, 0., size, rng),
             _ => panic!()
         }
     }
@@ -109,14 +406,15 @@ impl ShapeBehaviour for Rect {
         return format!("<R{:.6},{:.6},{:.6},{:.6},{}>", self.x, self.y, self.width, self.height, self.color.rgba());
     }
 
-    fn svg(&self, width: usize, height: usize, depth: usize) -> String {
+    fn svg(&self, width: usize, height: usize, depth: usize, palette: Option<&Palette>) -> String {
 		let mut out = String::new();
-		write!(&mut out, "<rect x='{}' y='{}' width='{}' height='{}' fill='{}' />",
+		write!(&mut out, "<rect x='{}' y='{}' width='{}' height='{}' {}{} />",
                 (self.x * width as f32) as i32,
                 (self.y * height as f32) as i32,
                 ((self.width) * width as f32) as i32,
                 ((self.height) * height as f32) as i32,
-                self.color.svg(depth))
+                fill(&self.color, depth, palette),
+                self.blend.svg())
 			.expect("String concat failed");
 		return out;
     }
@@ -133,7 +431,7 @@ impl ShapeBehaviour for Rect {
         let ymax = min(y2, canv.height as i32);
         for x in  xmin .. xmax {
             for y in ymin .. ymax {
-                canv.add_pixel(x, y, &self.color)
+                canv.blend_pixel(x, y, &self.color, self.blend)
             }
         }
     }
@@ -150,6 +448,7 @@ impl Hash for Rect {
         width.hash(state);
         height.hash(state);
         self.color.hash(state);
+        self.blend.hash(state);
     }
 }
 
@@ -163,38 +462,120 @@ pub struct Triangle {
     pub y1: f32,
     pub y2: f32,
     pub y3: f32,
-    pub color: Color
+    pub color: Color,
+    #[serde(default)]
+    pub blend: BlendMode,
+    #[serde(default)]
+    pub steps: StepSizes
 }
 
 impl Triangle {
-    pub fn random() -> Triangle {
+    pub fn random(ctx: &Context, rng: &mut Rng) -> Triangle {
+        let lo = -ctx.margin;
+        let hi = 1. + ctx.margin;
         Triangle {
-            x1: rand(),
-            x2: rand(),
-            x3: rand(),
-            y1: rand(),
-            y2: rand(),
-            y3: rand(),
-            color: Color {
-                r: randu8f(),
-                g: randu8f(),
-                b: randu8f(),
-                opacity: rand()
+            x1: rand_between(rng, lo, hi),
+            x2: rand_between(rng, lo, hi),
+            x3: rand_between(rng, lo, hi),
+            y1: rand_between(rng, lo, hi),
+            y2: rand_between(rng, lo, hi),
+            y3: rand_between(rng, lo, hi),
+            color: random_color(ctx, rng),
+            blend: BlendMode::Normal,
+            steps: StepSizes::default()
+        }
+    }
+
+    pub fn area(&self) -> f32 {
+        return ((self.x2 - self.x1) * (self.y3 - self.y1) -
+                (self.x3 - self.x1) * (self.y2 - self.y1)).abs() / 2.;
+    }
+
+    fn vertices(&self) -> [(f32, f32); 3] {
+        return [(self.x1, self.y1), (self.x2, self.y2), (self.x3, self.y3)];
+    }
+
+    /// The vertices of `d` reordered to best match ours, and the summed
+    /// distance between the pairs.
+    fn match_vertices(&self, d: &Triangle) -> ([(f32, f32); 3], f32) {
+        let ours = self.vertices();
+        let theirs = d.vertices();
+        let perms = [[0, 1, 2], [0, 2, 1], [1, 0, 2], [1, 2, 0], [2, 0, 1], [2, 1, 0]];
+        let mut best = (theirs, ::std::f32::MAX);
+        for p in perms.iter() {
+            let mut total = 0.;
+            for i in 0..3 {
+                let (x, y) = theirs[p[i]];
+                total += ((ours[i].0 - x).powi(2) + (ours[i].1 - y).powi(2)).sqrt();
             }
+            if total < best.1 {
+                best = ([theirs[p[0]], theirs[p[1]], theirs[p[2]]], total);
+            }
+        }
+        return best;
+    }
+
+    pub fn distance(&self, d: &Triangle) -> f32 {
+        return self.match_vertices(d).1 + self.color.distance(&d.color);
+    }
+
+    /// Average each vertex with its nearest counterpart
+    pub fn merge(&self, d: &Triangle) -> Triangle {
+        let (v, _) = self.match_vertices(d);
+        let a1 = self.area();
+        let a2 = d.area();
+        Triangle {
+            x1: (self.x1 + v[0].0) / 2.,
+            y1: (self.y1 + v[0].1) / 2.,
+            x2: (self.x2 + v[1].0) / 2.,
+            y2: (self.y2 + v[1].1) / 2.,
+            x3: (self.x3 + v[2].0) / 2.,
+            y3: (self.y3 + v[2].1) / 2.,
+            color: self.color.mix(&d.color, a2 / (a1 + a2).max(1e-6)),
+            blend: self.blend,
+            steps: self.steps.clone()
         }
     }
+
+    /// Cut along the median to the longest side
+    pub fn split(&self) -> (Triangle, Triangle) {
+        let v = self.vertices();
+        let len = |i: usize, j: usize| (v[i].0 - v[j].0).powi(2) + (v[i].1 - v[j].1).powi(2);
+        // Apex opposite the longest side
+        let apex = if len(1, 2) >= len(0, 2) && len(1, 2) >= len(0, 1) {
+            0
+        } else if len(0, 2) >= len(0, 1) {
+            1
+        } else {
+            2
+        };
+        let (p, q) = ((apex + 1) % 3, (apex + 2) % 3);
+        let mid = ((v[p].0 + v[q].0) / 2., (v[p].1 + v[q].1) / 2.);
+        let with = |a: (f32, f32), b: (f32, f32), c: (f32, f32)| Triangle {
+            x1: a.0, y1: a.1,
+            x2: b.0, y2: b.1,
+            x3: c.0, y3: c.1,
+            color: self.color.clone(),
+            blend: self.blend,
+            steps: self.steps.clone()
+        };
+        return (with(v[apex], v[p], mid), with(v[apex], mid, v[q]));
+    }
 }
 
 impl ShapeBehaviour for Triangle {
-    fn mutate(&mut self) {
-        match (rand() * 100.) as u8 {
-            0...40 => self.color = self.color.mutate(),
-            40...50 => self.x1 = rand_adjust(self.x1, 0.5, 0., 1.0),
-            50...60 => self.y1 = rand_adjust(self.y1, 0.5, 0., 1.0),
-            60...70 => self.x2 = rand_adjust(self.x2, 0.5, 0., 1.0),
-            70...80 => self.y2 = rand_adjust(self.y2, 0.5, 0., 1.0),
-            80...90 => self.x3 = rand_adjust(self.x3, 0.5, 0., 1.0),
-            90...100 => self.y3 = rand_adjust(self.y3, 0.5, 0., 1.0),
+    fn mutate(&mut self, ctx: &Context, rng: &mut Rng) {
+        let lo = -ctx.margin;
+        let hi = 1. + ctx.margin;
+        match ctx.rates.triangle.pick(6, rng) {
+            Gene::Color => self.color = mutate_color(&self.color, &mut self.steps, ctx, rng),
+            Gene::Blend => self.blend = BlendMode::random(rng),
+            Gene::Geometry(0) => self.x1 = self.steps.perturb_position(self.x1, lo, hi, rng),
+            Gene::Geometry(1) => self.y1 = self.steps.perturb_position(self.y1, lo, hi, rng),
+            Gene::Geometry(2) => self.x2 = self.steps.perturb_position(self.x2, lo, hi, rng),
+            Gene::Geometry(3) => self.y2 = self.steps.perturb_position(self.y2, lo, hi, rng),
+            Gene::Geometry(4) => self.x3 = self.steps.perturb_position(self.x3, lo, hi, rng),
+            Gene::Geometry(5) => self.y3 = self.steps.perturb_position(self.y3, lo, hi, rng),
             _ => panic!()
         }
     }
@@ -206,16 +587,17 @@ impl ShapeBehaviour for Triangle {
                                                         self.color.rgba());
     }
 
-    fn svg(&self, width: usize, height: usize, depth: usize) -> String {
+    fn svg(&self, width: usize, height: usize, depth: usize, palette: Option<&Palette>) -> String {
 		let mut out = String::new();
-		write!(&mut out, "<polygon points='{},{} {},{} {},{}' fill='{}' />",
+		write!(&mut out, "<polygon points='{},{} {},{} {},{}' {}{} />",
                 (self.x1 * width as f32) as i32,
                 (self.y1 * height as f32) as i32,
                 (self.x2 * width as f32) as i32,
                 (self.y2 * height as f32) as i32,
                 (self.x3 * width as f32) as i32,
                 (self.y3 * height as f32) as i32,
-                self.color.svg(depth))
+                fill(&self.color, depth, palette),
+                self.blend.svg())
 			.expect("String concat failed");
 		return out;
     }
@@ -228,9 +610,9 @@ impl ShapeBehaviour for Triangle {
         let y2 = (self.y2 * canv.height as f32) as i32;
         let x3 = (self.x3 * canv.width as f32) as i32;
         let y3 = (self.y3 * canv.height as f32) as i32;
-        let xmin = min(x1, min(x2, x3));
+        let xmin = max(min(x1, min(x2, x3)), 0);
         let xmax = min(max(x1, max(x2, x3)), canv.width as i32);
-        let ymin = min(y1, min(y2, y3));
+        let ymin = max(min(y1, min(y2, y3)), 0);
         let ymax = min(max(y1, max(y2, y3)), canv.height as i32);
         
         for x in xmin .. xmax  {
@@ -240,7 +622,7 @@ impl ShapeBehaviour for Triangle {
                 let sab = (x2 - x1) * asy - (y2 - y1) * asx > 0;
                 if ((x3 - x1) * asy - (y3 - y1) * asx > 0) == sab { continue };
                 if ((x3 - x2) * (y - y2) - (y3 - y2) * (x - x2) > 0) != sab { continue };
-                canv.add_pixel(x, y, &self.color)
+                canv.blend_pixel(x, y, &self.color, self.blend)
             }
         }
     }
@@ -261,6 +643,7 @@ impl Hash for Triangle {
         x3.hash(state);
         y3.hash(state);
         self.color.hash(state);
+        self.blend.hash(state);
     }
 }
 
@@ -271,32 +654,57 @@ pub struct Circle {
 	pub x: f32,
 	pub y: f32,
 	pub rad: f32,
-    pub color: Color
+    pub color: Color,
+    #[serde(default)]
+    pub blend: BlendMode,
+    #[serde(default)]
+    pub steps: StepSizes
 }
 
 impl Circle {
-	pub fn random() -> Circle {
+	pub fn random(ctx: &Context, rng: &mut Rng) -> Circle {
 		Circle {
-			x: rand(),
-			y: rand(),
-			rad: rand(),
-            color: Color {
-                r: randu8f(),
-                g: randu8f(),
-                b: randu8f(),
-                opacity: rand()
-            }
+			x: rand_between(rng, -ctx.margin, 1. + ctx.margin),
+			y: rand_between(rng, -ctx.margin, 1. + ctx.margin),
+			rad: rand(rng),
+            color: random_color(ctx, rng),
+            blend: BlendMode::Normal,
+            steps: StepSizes::default()
 		}
 	}
 
-    /*
-    pub fn merge(&mut self, d: Circle) {
-        self.color = (&self.color + &d.color) * 0.5;
-        self.x = (self.x + d.x) / 2.;
-        self.y = (self.y + d.y) / 2.;
-        self.rad = (self.rad + d.rad) / 2.;
+    pub fn distance(&self, d: &Circle) -> f32 {
+        return ((self.x - d.x).powi(2) + (self.y - d.y).powi(2)).sqrt() +
+               (self.rad - d.rad).abs() +
+               self.color.distance(&d.color);
+    }
+
+    /// Two overlapping circles either side of the centre
+    pub fn split(&self, rng: &mut Rng) -> (Circle, Circle) {
+        let angle = rand(rng) * 2. * ::std::f32::consts::PI;
+        let dx = angle.cos() * self.rad * 0.4;
+        let dy = angle.sin() * self.rad * 0.4;
+        let mut a = self.clone();
+        let mut b = self.clone();
+        a.rad = (self.rad * 0.7).max(0.01);
+        b.rad = a.rad;
+        a.x -= dx;
+        a.y -= dy;
+        b.x += dx;
+        b.y += dy;
+        return (a, b);
+    }
+
+    pub fn merge(&self, d: &Circle) -> Circle {
+        Circle {
+            x: (self.x + d.x) / 2.,
+            y: (self.y + d.y) / 2.,
+            rad: (self.rad + d.rad) / 2.,
+            color: (&self.color + &d.color) * 0.5,
+            blend: self.blend,
+            steps: self.steps.clone()
+        }
     }
-    */
 
     #[inline(never)]
     pub fn draw_onto_slow(&self, canvas: &mut Canvas) {
@@ -312,7 +720,7 @@ impl Circle {
                     let py = cy + y;
                     if px >= 0 && px < canvas.width as i32 &&
                        py >= 0 && py < canvas.height as i32 {
-                        canvas.add_pixel(px, py, &self.color);
+                        canvas.blend_pixel(px, py, &self.color, self.blend);
                     }
                 }
 
@@ -322,24 +730,28 @@ impl Circle {
 }
 
 impl ShapeBehaviour for Circle {
-    fn mutate(&mut self) {
-        match (rand() * 10.) as u8 {
-            0...4 => self.color = self.color.mutate(),
-            4...6 => self.x = rand_adjust(self.x, 0.5, 0., 1.0),
-            6...8 => self.y = rand_adjust(self.y, 0.5, 0., 1.0),
-            8...10 => self.rad = rand_adjust(self.rad, 0.5, 0.01, 1.0),
+    fn mutate(&mut self, ctx: &Context, rng: &mut Rng) {
+        let lo = -ctx.margin;
+        let hi = 1. + ctx.margin;
+        let size = 1. + 2. * ctx.margin;
+        match ctx.rates.circle.pick(3, rng) {
+            Gene::Color => self.color = mutate_color(&self.color, &mut self.steps, ctx, rng),
+            Gene::Blend => self.blend = BlendMode::random(rng),
+            Gene::Geometry(0) => self.x = self.steps.perturb_position(self.x, lo, hi, rng),
+            Gene::Geometry(1) => self.y = self.steps.perturb_position(self.y, lo, hi, rng),
+            Gene::Geometry(2) => self.rad = self.steps.perturb_position(self.rad, 0.01, size, rng),
             _ => panic!()
         }
     }
 
 
-	fn svg(&self, width: usize, height: usize, depth: usize) -> String {
+	fn svg(&self, width: usize, height: usize, depth: usize, palette: Option<&Palette>) -> String {
 		let mut out = String::new();
 		let cx = (self.x * width as f32) as i32;
 		let cy = (self.y * height as f32) as i32;
 		let rad = (self.rad * width as f32) as i32;
-		write!(&mut out, "<circle cx='{}' cy='{}' r='{}' fill='{}' />",
-                cx, cy, rad, self.color.svg(depth))
+		write!(&mut out, "<circle cx='{}' cy='{}' r='{}' {}{} />",
+                cx, cy, rad, fill(&self.color, depth, palette), self.blend.svg())
 			.expect("String concat failed");
 		return out;
 	}
@@ -360,10 +772,10 @@ impl ShapeBehaviour for Circle {
         let mut err = dx - (rad << 1);
 
         while x >= y {
-            canvas.line_add(cx - x, cx + x, cy + y, &self.color);
-            canvas.line_add(cx - x, cx + x, cy - y, &self.color);
-            canvas.line_add(cx - y, cx + y, cy + x, &self.color);
-            canvas.line_add(cx - y, cx + y, cy - x, &self.color);
+            canvas.line_add(cx - x, cx + x, cy + y, &self.color, self.blend);
+            canvas.line_add(cx - x, cx + x, cy - y, &self.color, self.blend);
+            canvas.line_add(cx - y, cx + y, cy + x, &self.color, self.blend);
+            canvas.line_add(cx - y, cx + y, cy - x, &self.color, self.blend);
 
             if err <= 0 {
                 y += 1;
@@ -391,6 +803,7 @@ impl Hash for Circle{
         y.hash(state);
         rad.hash(state);
         self.color.hash(state);
+        self.blend.hash(state);
     }
 }
 
@@ -413,7 +826,9 @@ mod tests {
                 g: 200,
                 b: 250,
                 opacity: 1.
-            }
+            },
+            blend: BlendMode::Normal,
+            steps: StepSizes::default()
 		};//Circle::random();
         s.draw_onto(&mut c);
         s.draw_onto_slow(&mut c2);
@@ -421,4 +836,63 @@ mod tests {
         //c2.save("test2.png");
         //assert_eq!(c.pixels, c2.pixels);
     }
+
+    #[test]
+    fn merge_matches_vertices(){
+        let color = Color { r: 10., g: 20., b: 30., opacity: 0.5 };
+        let a = Triangle { x1: 0.1, y1: 0.1, x2: 0.9, y2: 0.1, x3: 0.5, y3: 0.9,
+                           color: color.clone(), blend: BlendMode::Normal, steps: StepSizes::default() };
+        let b = Triangle { x1: 0.5, y1: 0.9, x2: 0.1, y2: 0.1, x3: 0.9, y3: 0.1,
+                           color: color, blend: BlendMode::Normal, steps: StepSizes::default() };
+        assert!(a.distance(&b) < 1e-6);
+        assert_eq!(a.merge(&b), a);
+    }
+
+    #[test]
+    fn split_keeps_footprint(){
+        let color = Color { r: 10., g: 20., b: 30., opacity: 0.5 };
+        let t = Triangle { x1: 0.1, y1: 0.1, x2: 0.9, y2: 0.2, x3: 0.4, y3: 0.9,
+                           color: color.clone(), blend: BlendMode::Normal, steps: StepSizes::default() };
+        let (a, b) = t.split();
+        assert!((a.area() + b.area() - t.area()).abs() < 1e-6);
+        let r = Rect { x: 0.1, y: 0.2, width: 0.6, height: 0.2,
+                       color: color, blend: BlendMode::Normal, steps: StepSizes::default() };
+        let (a, b) = r.split();
+        assert_eq!(a.width, 0.3);
+        assert_eq!(b.x, 0.4);
+    }
+
+    #[test]
+    fn draw_beyond_edges(){
+        let color = Color { r: 255., g: 255., b: 255., opacity: 1. };
+        let t = Triangle {
+            x1: -0.25, y1: -0.25,
+            x2: 1.25, y2: 0.5,
+            x3: 0.5, y3: 1.25,
+            color: color.clone(),
+            blend: BlendMode::Normal,
+            steps: StepSizes::default()
+        };
+        // As wide as a margin of 0.25 allows
+        let r = Rect { x: -0.25, y: 0.9, width: 1.5, height: 0.5, color: color.clone(), blend: BlendMode::Normal, steps: StepSizes::default() };
+        let ci = Circle { x: 1.1, y: -0.1, rad: 0.3, color: color, blend: BlendMode::Normal, steps: StepSizes::default() };
+
+        let lit = |shape: &dyn ShapeBehaviour, points: &[(i32, i32)]| -> Vec<f32> {
+            let mut c = Canvas::new(20, 20, 3);
+            shape.draw_onto(&mut c);
+            return points.iter().map(|&(x, y)| c.pixel_at(x, y).r).collect();
+        };
+        assert_eq!(lit(&t, &[(1, 1), (10, 10), (0, 19), (19, 19)]), vec![255., 255., 0., 0.]);
+        assert_eq!(lit(&r, &[(1, 18), (19, 19), (1, 17), (10, 1)]), vec![255., 255., 0., 0.]);
+        assert_eq!(lit(&ci, &[(19, 1), (19, 2), (15, 1), (10, 10)]), vec![255., 255., 0., 0.]);
+    }
+
+    #[test]
+    fn set_params_clamps(){
+        let color = Color { r: 10., g: 20., b: 30., opacity: 0.5 };
+        let mut s = Shape::Rect(Rect { x: 0.1, y: 0.2, width: 0.6, height: 0.2,
+                                       color: color, blend: BlendMode::Normal, steps: StepSizes::default() });
+        s.set_params(&[-3., 0.5, 9., -1., 2., 0.5, 0.5, 1.5], 0.1);
+        assert_eq!(s.params(), vec![-0.1, 0.5, 1.2, 0., 1., 0.5, 0.5, 1.]);
+    }
 }
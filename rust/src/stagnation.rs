@@ -0,0 +1,141 @@
+// When and how to start over after the search stops improving. The
+// limits follow darwin-rs's reset_limit_* settings so both engines take
+// the same flags.
+
+/// What a restart does to the population
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Restart {
+    /// Back to a bare background
+    Wipe,
+    /// Copies of the best, each given this many mutations
+    Perturb(usize),
+    /// Copies of the best with the top k shapes dropped
+    Truncate(usize),
+    /// Replace this fraction of the population, worst first, with fresh
+    /// random genomes
+    Reseed(f32),
+    /// Multiply every shape's step sizes by this
+    Boost(f32)
+}
+
+impl Default for Restart {
+    fn default() -> Restart {
+        Restart::Wipe
+    }
+}
+
+impl Restart {
+    /// A policy by name, with its amount or a default
+    pub fn parse(s: &str, amount: Option<f32>) -> Option<Restart> {
+        match s {
+            "wipe" => Some(Restart::Wipe),
+            "perturb" => Some(Restart::Perturb(amount.unwrap_or(10.) as usize)),
+            "truncate" => Some(Restart::Truncate(amount.unwrap_or(5.) as usize)),
+            "reseed" => Some(Restart::Reseed(amount.unwrap_or(0.5).min(1.).max(0.))),
+            "boost" => Some(Restart::Boost(amount.unwrap_or(4.))),
+            _ => None
+        }
+    }
+
+    /// Whether the population starts over from copies of the best
+    pub fn from_best(&self) -> bool {
+        match *self {
+            Restart::Perturb(_) | Restart::Truncate(_) => true,
+            _ => false
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Stagnation {
+    /// Generations without a new best before the first restart
+    pub start: u64,
+    /// Added to the limit after each restart
+    pub increment: u64,
+    /// Once the limit reaches this it drops back to `start`. 0 never
+    /// restarts.
+    pub end: u64
+}
+
+impl Default for Stagnation {
+    fn default() -> Stagnation {
+        Stagnation {
+            start: 100,
+            increment: 100,
+            end: 0
+        }
+    }
+}
+
+/// Counts generations since the last new best
+#[derive(Debug, Clone)]
+pub struct Stall {
+    cfg: Stagnation,
+    limit: u64,
+    stale: u64,
+    pub restarts: u64
+}
+
+impl Stall {
+    pub fn new(cfg: Stagnation) -> Stall {
+        return Stall {
+            limit: cfg.start,
+            cfg: cfg,
+            stale: 0,
+            restarts: 0
+        };
+    }
+
+    pub fn improved(&mut self) {
+        self.stale = 0;
+    }
+
+    /// Note a generation without improvement, true if it is time to
+    /// restart
+    pub fn stalled(&mut self) -> bool {
+        if self.cfg.end == 0 {
+            return false;
+        }
+        self.stale += 1;
+        if self.stale <= self.limit {
+            return false;
+        }
+        self.stale = 0;
+        self.restarts += 1;
+        self.limit += self.cfg.increment;
+        if self.limit >= self.cfg.end {
+            self.limit = self.cfg.start;
+        }
+        return true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Stagnation, Stall, Restart};
+
+    fn wait(stall: &mut Stall) -> u64 {
+        stall.improved();
+        let mut n = 1;
+        while !stall.stalled() {
+            n += 1;
+        }
+        return n;
+    }
+
+    #[test]
+    fn limits_grow_then_wrap(){
+        let mut stall = Stall::new(Stagnation { start: 2, increment: 2, end: 6 });
+        assert_eq!(wait(&mut stall), 3);
+        assert_eq!(wait(&mut stall), 5);
+        assert_eq!(wait(&mut stall), 3);
+        assert_eq!(stall.restarts, 3);
+
+        let mut never = Stall::new(Stagnation::default());
+        assert!((0..1000).all(|_| !never.stalled()));
+
+        assert_eq!(Restart::parse("truncate", Some(3.)), Some(Restart::Truncate(3)));
+        assert_eq!(Restart::parse("reseed", Some(2.)), Some(Restart::Reseed(1.)));
+        assert_eq!(Restart::parse("nope", None), None);
+    }
+}
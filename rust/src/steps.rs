@@ -0,0 +1,57 @@
+use rando::{Rng, rand_gauss, rand_adjust};
+
+// Learning rate for the log-normal self-adaptation
+const TAU: f32 = 0.3;
+const MIN_STEP: f32 = 0.0001;
+const MAX_STEP: f32 = 0.5;
+
+/// Evolution strategy style step sizes. Each shape carries its own, they
+/// are perturbed log-normally before every mutation of the gene group
+/// they control and are inherited along with the shape, so lineages that
+/// benefit from small steps keep them.
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize)]
+pub struct StepSizes {
+    pub position: f32, // Standard deviation as a fraction of the image
+    pub color: f32, // Standard deviation as a fraction of 256
+    pub opacity: f32
+}
+
+impl Default for StepSizes {
+    fn default() -> StepSizes {
+        StepSizes {
+            position: 0.15,
+            color: 0.03,
+            opacity: 0.03
+        }
+    }
+}
+
+#[inline]
+fn adapt(step: f32, rng: &mut Rng) -> f32 {
+    return (step * (TAU * rand_gauss(rng)).exp()).min(MAX_STEP).max(MIN_STEP);
+}
+
+impl StepSizes {
+    pub fn adapt_position(&mut self, rng: &mut Rng) -> f32 {
+        self.position = adapt(self.position, rng);
+        return self.position;
+    }
+
+    /// Adapt the position step, then move `p` by a step of that size
+    pub fn perturb_position(&mut self, p: f32, min: f32, max: f32, rng: &mut Rng) -> f32 {
+        let sigma = self.adapt_position(rng);
+        return rand_adjust(rng, p, sigma, min, max);
+    }
+
+    /// Multiply every step, within the usual bounds
+    pub fn scale(&mut self, factor: f32) {
+        self.position = (self.position * factor).min(MAX_STEP).max(MIN_STEP);
+        self.color = (self.color * factor).min(MAX_STEP).max(MIN_STEP);
+        self.opacity = (self.opacity * factor).min(MAX_STEP).max(MIN_STEP);
+    }
+
+    pub fn adapt_color(&mut self, rng: &mut Rng) {
+        self.color = adapt(self.color, rng);
+        self.opacity = adapt(self.opacity, rng);
+    }
+}